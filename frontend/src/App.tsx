@@ -3,10 +3,10 @@ import logo from './logo.svg';
 import './App.css';
 
 // Icons
-import { ArrowClockwise, StopFill, PlayFill, VolumeUp, Trash, Pencil, ClockHistory, MusicNote } from 'react-bootstrap-icons';
+import { ArrowClockwise, StopFill, PauseFill, PlayFill, VolumeUp, Trash, Pencil, ClockHistory, MusicNote } from 'react-bootstrap-icons';
 
 import { AppContextProvider, useAppContext } from './App/AppContext';
-import { PlayingState, Recording, RecordingId } from './App/State';
+import { ActionType, PlayingState, Recording, RecordingId } from './App/State';
 import { Button, Alert, Spinner, ButtonToolbar, ButtonGroup, Navbar, Container, ListGroup, Stack, Modal, Nav, Offcanvas, ListGroupItem } from 'react-bootstrap';
 
 function App() {
@@ -55,6 +55,11 @@ function App() {
                     <Nav.Link>By title</Nav.Link>
                   </Nav.Item>
                   <hr/>
+                  <Nav.Item className="fw-bold">
+                    Devices
+                  </Nav.Item>
+                  <DeviceList />
+                  <hr/>
                   <Nav.Item>
                     <Nav.Link>About</Nav.Link>
                   </Nav.Item>
@@ -66,6 +71,8 @@ function App() {
         </Navbar>
         <Container className="mt-2 px-0 px-sm-2">
           <ErrorBanner />
+          <NoticeBanner />
+          <DeviceBanner />
           <RecordingsList />
         </Container>
       </AppContextProvider>
@@ -231,7 +238,7 @@ function RecordingsList() {
       </Modal>
 
       <Stack className="mb-3">
-        {state.isRecording
+        {state.recordingDevices.length > 0
           ? (
             <ListGroup>
               <ListGroup.Item key="recording">
@@ -264,10 +271,47 @@ function RecordingsList() {
           ))}
           </ListGroup>
         ))}
+        <MoreRecordings />
       </Stack></>
   )
 }
 
+/// Loads the next page of recordings once it is scrolled into view, or when clicked.
+function MoreRecordings() {
+  const { state, actions, dispatch } = useAppContext();
+  const sentinel = useRef(null as HTMLDivElement | null);
+
+  const cursor = state.recordingsCursor;
+  const loading = state.recordingsLoading;
+
+  useEffect(
+    () => {
+      const element = sentinel.current;
+      if(element === null || cursor === null || loading) {
+        return;
+      }
+      const observer = new IntersectionObserver(entries => {
+        if(entries.some(entry => entry.isIntersecting)) {
+          actions.loadMoreRecordings(dispatch, cursor);
+        }
+      });
+      observer.observe(element);
+      return () => observer.disconnect();
+    },
+    [sentinel, cursor, loading, actions, dispatch]
+  )
+
+  return cursor !== null ? (
+    <div ref={sentinel} className="mt-2 text-center">
+      {
+        loading
+          ? <Spinner animation="border" role="status" />
+          : <Button variant="outline-primary" onClick={() => actions.loadMoreRecordings(dispatch, cursor)}>Load more</Button>
+      }
+    </div>
+  ) : <></>
+}
+
 type RecordingItemProps = {
   recording: Recording,
   playingState: PlayingState,
@@ -334,6 +378,47 @@ function ErrorBanner() {
 }
 
 
+function NoticeBanner() {
+  const { state, dispatch } = useAppContext();
+
+  return state.notice !== null ? (
+    <Alert key="notice" variant="info" dismissible
+      onClose={() => dispatch({ type: ActionType.DismissNotice })}>
+      {state.notice}
+    </Alert>
+  ) : <></>
+}
+
+
+function DeviceBanner() {
+  const { state } = useAppContext();
+
+  const listening = state.devices?.some(dev => dev.connected && dev.listening);
+  return state.devices !== null && !listening ? (
+    <Alert key="devices" variant="warning">
+      No recording device connected
+    </Alert>
+  ) : <></>
+}
+
+
+function DeviceList() {
+  const { state } = useAppContext();
+
+  return (
+    <>
+      {(state.devices ?? []).map(dev => (
+        <Nav.Item key={dev.id} className={dev.connected ? "" : "text-muted"}>
+          {dev.client_name} ({dev.port_name})
+          {' '}&ndash;{' '}
+          {!dev.connected ? "disconnected" : dev.recording ? "recording" : dev.listening ? "listening" : "connected"}
+        </Nav.Item>
+      ))}
+    </>
+  )
+}
+
+
 function Toolbar({ className }: { className: string }) {
   const { state, actions, dispatch } = useAppContext();
 
@@ -364,6 +449,14 @@ function Toolbar({ className }: { className: string }) {
                 disabled={state.playingState === PlayingState.Stopped}
                 onClick={() => actions.stopPlaying(dispatch)}><StopFill /></Button>)
         }
+        {
+          state.playingState === PlayingState.Playing || state.playingState === PlayingState.Paused
+            ? (<Button variant="outline-primary"
+                onClick={() => actions.pausePlaying(dispatch, state.playingState === PlayingState.Playing)}>
+                {state.playingState === PlayingState.Playing ? <PauseFill /> : <PlayFill />}
+              </Button>)
+            : <></>
+        }
       </ButtonGroup>
     </ButtonToolbar>
   )
@@ -49,15 +49,23 @@ export const AppContextProvider = React.memo(({ children }: Props) => {
                                 recording_id: null,
                             });
                             break;
+                        case "PlayPause":
+                            dispatch({ type: State.ActionType.PlayPaused });
+                            break;
+                        case "PlayResume":
+                            dispatch({ type: State.ActionType.PlayResumed });
+                            break;
                         case "RecordBegin":
                             dispatch({
                                 type: State.ActionType.RecordBegin,
+                                device: data.device,
                             });
                             break;
                         case "RecordEnd":
                             dispatch({
                                 type: State.ActionType.RecordEnd,
                                 recording: data.recording,
+                                device: data.device,
                             });
                             break;
                         case "RecordUpdate":
@@ -72,10 +80,33 @@ export const AppContextProvider = React.memo(({ children }: Props) => {
                                 recording_id: data.recording_id,
                             });
                             break;
+                        case "RecordDiscard":
+                            dispatch({
+                                type: State.ActionType.RecordDiscard,
+                                device: data.device,
+                            });
+                            break;
+                        case "RecordShort":
+                            const notes = data.note_count === 1 ? "1 note" : `${data.note_count} notes`;
+                            dispatch({
+                                type: State.ActionType.RecordShort,
+                                device: data.device,
+                                notice: data.recording
+                                    ? `Take hidden as too short (${notes})`
+                                    : `Take discarded (${notes})`,
+                            });
+                            break;
+                        case "DeviceConnect":
+                        case "DeviceDisconnect":
+                        case "ListenBegin":
+                        case "ListenEnd":
+                            State.actions.queryDevices(dispatch);
+                            break;
                         case "RecordError":
                             dispatch({
                                 type: State.ActionType.RecordError,
                                 errorMessage: data.message,
+                                device: data.device,
                             });
                             break;
                     }
@@ -89,6 +120,7 @@ export const AppContextProvider = React.memo(({ children }: Props) => {
         connectEventSource();
         State.actions.queryRecordings(dispatch);
         State.actions.queryPlayState(dispatch);
+        State.actions.queryDevices(dispatch);
 
         return () => {
             if(eventSourceRef.current) {
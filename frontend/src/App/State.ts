@@ -2,10 +2,14 @@ enum ActionType {
     QueryRecordingsPending,
     QueryRecordingsFailed,
     QueryRecordingsSucceeded,
+    QueryMoreRecordingsSucceeded,
 
     RecordBegin,
     RecordEnd,
+    RecordDiscard,
+    RecordShort,
     RecordError,
+    DismissNotice,
 
     RecordDelete,
     RecordDeleteError,
@@ -16,6 +20,10 @@ enum ActionType {
     PlayControlPending,
     PlayStateUpdated,
     PlayStateFailed,
+    PlayPaused,
+    PlayResumed,
+
+    DevicesUpdated,
 }
 
 type RecordingId = number;
@@ -36,46 +44,75 @@ type WireRecording = {
     note_count: number,
 };
 
+type Device = {
+    id: string,
+    client_name: string,
+    port_name: string,
+    connected: boolean,
+    listening: boolean,
+    recording: boolean,
+};
+
 type Action = {
     type: ActionType,
     recordings?: Array<WireRecording>,
+    cursor?: string | null,
     recording?: WireRecording,
     errorMessage?: string,
+    notice?: string,
     recording_id?: RecordingId | null,
+    device?: string,
+    devices?: Array<Device>,
+    paused?: boolean,
 }
 
 enum PlayingState {
     Stopped,
     Playing,
+    Paused,
     Pending,
 }
 
 type AppState = {
     recordings: Array<Recording>,
     recordingsLoading: boolean,
+    /// Cursor of the next page of recordings, null if all of them have been loaded
+    recordingsCursor: string | null,
 
     error: boolean,
     errorMessage: string,
 
+    /// Informational message, e.g. about a discarded take
+    notice: string | null,
+
     playingState: PlayingState,
     playingRecording: RecordingId | null,
     playingQueued: RecordingId | null,
 
-    isRecording: boolean,
+    /// Devices on which a recording is in progress
+    recordingDevices: Array<string>,
+
+    /// All MIDI devices seen by the server, null until loaded
+    devices: Array<Device> | null,
 };
 
 const initialState: AppState = {
     recordings: [],
     recordingsLoading: false,
+    recordingsCursor: null,
 
     error: false,
     errorMessage: "",
 
+    notice: null,
+
     playingState: PlayingState.Pending,
     playingRecording: null,
     playingQueued: null,
 
-    isRecording: false,
+    recordingDevices: [],
+
+    devices: null,
 };
 
 type ActionDispatch = (a: Action) => void;
@@ -106,7 +143,27 @@ const actions = {
             const response = await fetch("/recordings");
             await checkForStatus(response);
             const data = await response.json();
-            dispatch({ type: ActionType.QueryRecordingsSucceeded, recordings: data });
+            dispatch({
+                type: ActionType.QueryRecordingsSucceeded,
+                recordings: data.recordings,
+                cursor: data.next_cursor,
+            });
+        } catch (e) {
+            dispatch({ type: ActionType.QueryRecordingsFailed, errorMessage: (e as object).toString() });
+        }
+    },
+
+    loadMoreRecordings: async (dispatch: ActionDispatch, cursor: string) => {
+        dispatch({ type: ActionType.QueryRecordingsPending });
+        try {
+            const response = await fetch(`/recordings?cursor=${encodeURIComponent(cursor)}`);
+            await checkForStatus(response);
+            const data = await response.json();
+            dispatch({
+                type: ActionType.QueryMoreRecordingsSucceeded,
+                recordings: data.recordings,
+                cursor: data.next_cursor,
+            });
         } catch (e) {
             dispatch({ type: ActionType.QueryRecordingsFailed, errorMessage: (e as object).toString() });
         }
@@ -147,12 +204,27 @@ const actions = {
             const response = await fetch("/play-status");
             await checkForStatus(response);
             const data = await response.json();
-            dispatch({ type: ActionType.PlayStateUpdated, recording_id: data });
+            dispatch({
+                type: ActionType.PlayStateUpdated,
+                recording_id: data === null ? null : data.recording,
+                paused: data !== null && data.paused,
+            });
         } catch (e) {
             dispatch({ type: ActionType.PlayStateFailed, errorMessage: (e as object).toString() });
         }
     },
 
+    queryDevices: async (dispatch: ActionDispatch) => {
+        try {
+            const response = await fetch("/devices");
+            await checkForStatus(response);
+            const devices = await response.json();
+            dispatch({ type: ActionType.DevicesUpdated, devices });
+        } catch (e) {
+            console.log(`Failed to query devices: ${e}`);
+        }
+    },
+
     playRecording: async (dispatch: ActionDispatch, recording: RecordingId) => {
         dispatch({ type: ActionType.PlayControlPending, recording_id: recording });
         try {
@@ -169,6 +241,21 @@ const actions = {
         }
     },
 
+    pausePlaying: async (dispatch: ActionDispatch, paused: boolean) => {
+        try {
+            const response = await fetch(paused ? "/pause" : "/resume", {
+              method: "POST",
+              headers: {
+                "Content-Type": "application/json",
+              },
+              body: JSON.stringify(null)
+            });
+            await checkForStatus(response);
+        } catch (e) {
+            dispatch({ type: ActionType.PlayStateFailed, errorMessage: (e as object).toString() });
+        }
+    },
+
     stopPlaying: async (dispatch: ActionDispatch) => {
         dispatch({ type: ActionType.PlayControlPending, recording_id: null });
         try {
@@ -198,6 +285,20 @@ function reducer(state: AppState, action: Action): AppState {
                 ...state,
                 recordings: action.recordings!.map(parseRecording),
                 recordingsLoading: false,
+                recordingsCursor: action.cursor!,
+                error: false,
+                errorMessage: "",
+            }
+        case ActionType.QueryMoreRecordingsSucceeded:
+            // Skip recordings that are already listed, e.g. because they were loaded by a refresh
+            const more = action.recordings!
+                .filter(wire => !state.recordings.some(rec => rec.id === wire.id))
+                .map(parseRecording);
+            return {
+                ...state,
+                recordings: [...state.recordings, ...more],
+                recordingsLoading: false,
+                recordingsCursor: action.cursor!,
                 error: false,
                 errorMessage: "",
             }
@@ -212,19 +313,35 @@ function reducer(state: AppState, action: Action): AppState {
         case ActionType.RecordBegin:
             return {
                 ...state,
-                isRecording: true,
+                recordingDevices: [...state.recordingDevices.filter(dev => dev !== action.device!), action.device!],
             }
 
         case ActionType.RecordEnd:
             return {
                 ...state,
                 recordings: [parseRecording(action.recording!), ...state.recordings],
-                isRecording: false,
+                recordingDevices: state.recordingDevices.filter(dev => dev !== action.device!),
+            }
+        case ActionType.RecordDiscard:
+            return {
+                ...state,
+                recordingDevices: state.recordingDevices.filter(dev => dev !== action.device!),
+            }
+        case ActionType.RecordShort:
+            return {
+                ...state,
+                recordingDevices: state.recordingDevices.filter(dev => dev !== action.device!),
+                notice: action.notice!,
+            }
+        case ActionType.DismissNotice:
+            return {
+                ...state,
+                notice: null,
             }
         case ActionType.RecordError:
             return {
                 ...state,
-                isRecording: false,
+                recordingDevices: state.recordingDevices.filter(dev => dev !== action.device!),
                 error: true,
                 errorMessage: action.errorMessage!,
             }
@@ -251,7 +368,6 @@ function reducer(state: AppState, action: Action): AppState {
                 ...state,
                 error: false,
                 recordings: updatedRecordings,
-                isRecording: false,
             }
         case ActionType.RecordUpdateError:
             return {
@@ -264,7 +380,19 @@ function reducer(state: AppState, action: Action): AppState {
             return {
                 ...state,
                 playingRecording: action.recording_id!,
-                playingState: action.recording_id! ? PlayingState.Playing : PlayingState.Stopped,
+                playingState: !action.recording_id
+                    ? PlayingState.Stopped
+                    : action.paused ? PlayingState.Paused : PlayingState.Playing,
+            }
+        case ActionType.PlayPaused:
+            return {
+                ...state,
+                playingState: PlayingState.Paused,
+            }
+        case ActionType.PlayResumed:
+            return {
+                ...state,
+                playingState: PlayingState.Playing,
             }
         case ActionType.PlayControlPending:
             return {
@@ -281,6 +409,13 @@ function reducer(state: AppState, action: Action): AppState {
                 error: true,
                 errorMessage: action.errorMessage!,
             }
+
+        case ActionType.DevicesUpdated:
+            return {
+                ...state,
+                devices: action.devices!,
+                recordingDevices: action.devices!.filter(dev => dev.recording).map(dev => dev.id),
+            }
         default:
             throw new Error(`Unknown action type: ${action.type}`)
     }
@@ -304,6 +439,7 @@ export {
 
     type Recording,
     type RecordingId,
+    type Device,
 
     type ActionDispatch,
     type Action,
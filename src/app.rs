@@ -1,34 +1,137 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    config::AppConfig,
-    midi::{self, encode_midi, Device, DeviceInfo, RecordEvent},
-    player::{self, MidiPlayQueue},
+    backup::{self, Backup},
+    config::{AppConfig, ShortTakeAction},
+    import,
+    journal::{self, Journal},
+    midi::{self, encode_midi, Device, DeviceInfo, DeviceRegistry, RecordEvent, TakeStats},
+    player::{
+        self, EntryId, MidiPlayQueue, PlayLoop, PlayOptions, PlayStatus, QueueEntry, QueueSettings,
+        RepeatMode,
+    },
     recorder,
-    store::{RecordingId, RecordingInfo, RecordingStore},
+    store::{
+        DataDirectoryLock, Piece, PieceId, RecordingId, RecordingInfo, RecordingPage,
+        RecordingQuery, RecordingStore, StopReason, TagCount,
+    },
 };
 
-use color_eyre::eyre::bail;
-use tokio::sync::{broadcast, Mutex};
-use tracing::{error, info};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{bail, eyre};
+use tokio::sync::{broadcast, oneshot, Mutex, Notify};
+use tracing::{debug, error, info, warn};
+
+/// How often to check the trash for recordings that are due to be purged.
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Tag for recordings marked as favourite with a gesture.
+const FAVOURITE_TAG: &str = "favourite";
+
+/// How often to check whether a scheduled backup is due.
+const BACKUP_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Delay before retrying to set up the recorder of a device, doubled after each failed attempt.
+const LISTEN_RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound for the delay between attempts to set up the recorder of a device.
+const LISTEN_RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// A request that can't be carried out as made, e.g. because of invalid parameters, so that
+/// callers can tell it apart from failures of the app itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRequest(pub String);
+
+impl std::fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidRequest {}
 
 #[derive(Debug)]
 pub struct Shared {
     config: AppConfig,
     change_tx: broadcast::Sender<StateChange>,
     state: Mutex<State>,
+    /// Signalled when the recorder of a device has shut down
+    listener_ended: Notify,
 }
 
 #[derive(Debug)]
 pub struct State {
-    listening_device: Option<Device>,
+    /// All devices seen so far, maintained by the device listener
+    devices: DeviceRegistry,
+    /// Devices that are being recorded from
+    listening_devices: HashMap<Device, Listener>,
+    /// Devices whose recorder couldn't be set up, and is being retried
+    listen_retries: HashSet<Device>,
+    /// Devices on which a song is being recorded right now
+    recording_devices: HashSet<Device>,
+    /// Device the player was last started on
+    playing_on: Option<Device>,
+    /// Options the player was last started with, also used for the following entries of the queue
+    play_options: PlayOptions,
+    /// Entry that was played until the end (and the player generation it was played in), while
+    /// waiting for the gap before the next entry of the queue
+    queue_finished: Option<(QueueEntry<RecordingId>, u64)>,
     player: player::MidiPlayQueue<RecordingId>,
     midi: midi::Manager,
     store: RecordingStore,
+    /// Keeps offline commands like restoring a backup from replacing the database
+    #[allow(unused)]
+    data_lock: DataDirectoryLock,
     #[allow(unused)]
     shutdown: broadcast::Sender<()>,
 }
 
+/// The recorder of a device.
+#[derive(Debug)]
+struct Listener {
+    info: DeviceInfo,
+    /// Tells the recorder to shut down, taken once used
+    stop: Option<oneshot::Sender<()>>,
+}
+
+/// A MIDI device seen by the app, and what the app is doing with it.
+#[derive(Debug, Clone)]
+pub struct DeviceStatus {
+    pub device: Device,
+    pub info: DeviceInfo,
+    pub connected: bool,
+    /// When the device was last connected or disconnected
+    pub changed_at: DateTime<Utc>,
+    /// Whether a recorder is attached to the device
+    pub listening: bool,
+    /// Whether a song is being recorded on the device right now
+    pub recording: bool,
+}
+
+/// What the player is doing with its queue.
+#[derive(Debug, Clone)]
+pub struct QueueStatus {
+    /// Entry being played right now
+    pub current: Option<QueueEntry<RecordingId>>,
+    /// Entries to be played next
+    pub entries: Vec<QueueEntry<RecordingId>>,
+    pub settings: QueueSettings,
+}
+
+/// Guess for what piece a recording might be.
+#[derive(Debug, Clone)]
+pub struct Classification {
+    /// Title of the piece, or name of the recordings if they are not assigned to a piece
+    pub name: String,
+    pub piece_id: Option<PieceId>,
+    /// Cosine similarity of the note histograms
+    pub similarity: f64,
+}
+
 #[derive(Debug, Clone)]
 pub struct App {
     shared: Arc<Shared>,
@@ -40,18 +143,42 @@ impl App {
 
         let (shutdown, shutdown_rx) = broadcast::channel::<()>(1);
 
+        if config.devices.is_empty() && config.midi_device.is_none() {
+            warn!("No devices are configured for recording");
+        }
+
+        let data_lock = DataDirectoryLock::acquire(&config.data_directory)?;
         let store = RecordingStore::open(&config.data_directory).await?;
 
+        // Recordings that were interrupted the last time the daemon ran
+        let journal_directory = config.journal_directory();
+        let recovered =
+            journal::recover_recordings(&store, &journal_directory, &config.short_takes).await?;
+        for rec in recovered {
+            info!("Recovered interrupted recording {}", rec.id.0);
+        }
+
         let midi = midi::Manager::new();
         let device_listener = midi.create_device_listener()?;
-        let player = MidiPlayQueue::new();
+        let player = MidiPlayQueue::new(QueueSettings {
+            repeat: RepeatMode::Off,
+            shuffle: false,
+            gap: Duration::from_secs_f64(config.playback.gap_seconds),
+        });
         let player_events = player.subscribe();
 
         let state = State {
-            listening_device: None,
+            devices: device_listener.registry(),
+            listening_devices: HashMap::new(),
+            listen_retries: HashSet::new(),
+            recording_devices: HashSet::new(),
+            playing_on: None,
+            play_options: PlayOptions::default(),
+            queue_finished: None,
             player,
             midi,
             store,
+            data_lock,
             shutdown,
         };
 
@@ -59,6 +186,7 @@ impl App {
             config,
             change_tx,
             state: Mutex::new(state),
+            listener_ended: Notify::new(),
         });
 
         // TODO: provide way to listen for failures of this threads
@@ -67,6 +195,16 @@ impl App {
             let shutdown_rx = shutdown_rx.resubscribe();
             async move { player_event_loop(shared, player_events, shutdown_rx).await }
         });
+        tokio::spawn({
+            let shared = shared.clone();
+            let shutdown_rx = shutdown_rx.resubscribe();
+            async move { trash_purge_loop(shared, shutdown_rx).await }
+        });
+        tokio::spawn({
+            let shared = shared.clone();
+            let shutdown_rx = shutdown_rx.resubscribe();
+            async move { backup_loop(shared, shutdown_rx).await }
+        });
         tokio::spawn({
             let shared = shared.clone();
             async move { midi_event_loop(shared, device_listener, shutdown_rx).await }
@@ -79,9 +217,30 @@ impl App {
         self.shared.change_tx.subscribe()
     }
 
-    pub async fn query_recordings(&self) -> color_eyre::Result<Vec<RecordingInfo>> {
+    /// All MIDI devices seen since the app started, including disconnected ones.
+    pub async fn devices(&self) -> Vec<DeviceStatus> {
         let state = self.shared.state.lock().await;
-        state.store.get_recording_infos().await
+        state
+            .devices
+            .devices()
+            .into_iter()
+            .map(|known| DeviceStatus {
+                listening: state.listening_devices.contains_key(&known.device),
+                recording: state.recording_devices.contains(&known.device),
+                device: known.device,
+                info: known.info,
+                connected: known.connected,
+                changed_at: known.changed_at,
+            })
+            .collect()
+    }
+
+    pub async fn query_recordings(
+        &self,
+        query: RecordingQuery,
+    ) -> color_eyre::Result<RecordingPage> {
+        let state = self.shared.state.lock().await;
+        state.store.query_recording_infos(query).await
     }
 
     pub async fn delete_recording(&self, recording: RecordingId) -> color_eyre::Result<()> {
@@ -93,15 +252,118 @@ impl App {
         Ok(())
     }
 
-    pub async fn rename_recording(
+    /// Return the info of a recording together with its data as standard MIDI file.
+    pub async fn get_recording_midi(
+        &self,
+        recording: RecordingId,
+    ) -> color_eyre::Result<(RecordingInfo, Vec<u8>)> {
+        let state = self.shared.state.lock().await;
+        let rec = state
+            .store
+            .get_active_recording_info_by_id(recording)
+            .await?;
+        let data = state.store.get_recording_midi(recording).await?;
+        Ok((rec, data))
+    }
+
+    /// Import MIDI files (that have already been normalised) as new recordings.
+    pub async fn import_recordings(
+        &self,
+        recordings: Vec<(Vec<RecordEvent>, DateTime<Utc>)>,
+    ) -> color_eyre::Result<Vec<RecordingInfo>> {
+        let state = self.shared.state.lock().await;
+        let mut imported = Vec::with_capacity(recordings.len());
+        for (events, created_at) in recordings {
+            let recording = state
+                .store
+                .insert_recording(encode_midi(&events), created_at, None, None, false, false)
+                .await?;
+            info!("Imported recording with id {}", recording.id.0);
+            self.shared.notify(StateChange::RecordImport {
+                recording: recording.clone(),
+            });
+            imported.push(recording);
+        }
+        Ok(imported)
+    }
+
+    /// Handle to the store for long-running operations that shouldn't block the app state.
+    pub async fn recording_store(&self) -> RecordingStore {
+        let state = self.shared.state.lock().await;
+        state.store.clone()
+    }
+
+    pub async fn query_trash(&self) -> color_eyre::Result<Vec<RecordingInfo>> {
+        let state = self.shared.state.lock().await;
+        state.store.get_trashed_recording_infos().await
+    }
+
+    /// Back up the database right away, and delete backups that are no longer needed.
+    pub async fn create_backup(&self) -> color_eyre::Result<Backup> {
+        self.shared.create_backup().await
+    }
+
+    pub async fn restore_recording(
         &self,
         recording: RecordingId,
-        new_name: String,
     ) -> color_eyre::Result<RecordingInfo> {
+        let state = self.shared.state.lock().await;
+        state.store.restore_recording_by_id(recording).await?;
+        let rec = state.store.get_recording_info_by_id(recording).await?;
+        self.shared.notify(StateChange::RecordRestore {
+            recording: rec.clone(),
+        });
+        Ok(rec)
+    }
+
+    pub async fn search_recordings(
+        &self,
+        search: &str,
+        include_short: bool,
+        limit: u32,
+    ) -> color_eyre::Result<Vec<RecordingInfo>> {
         let state = self.shared.state.lock().await;
         state
             .store
-            .rename_recording_by_id(recording, new_name)
+            .search_recording_infos(search, include_short, limit)
+            .await
+    }
+
+    pub async fn update_recording(
+        &self,
+        recording: RecordingId,
+        new_name: Option<String>,
+        new_notes: Option<String>,
+    ) -> color_eyre::Result<RecordingInfo> {
+        let state = self.shared.state.lock().await;
+        if let Some(new_name) = new_name {
+            state
+                .store
+                .rename_recording_by_id(recording, new_name)
+                .await?;
+        }
+        if let Some(new_notes) = new_notes {
+            state
+                .store
+                .set_recording_notes_by_id(recording, new_notes)
+                .await?;
+        }
+        let rec = state.store.get_recording_info_by_id(recording).await?;
+        self.shared.notify(StateChange::RecordUpdate {
+            recording: rec.clone(),
+        });
+        Ok(rec)
+    }
+
+    pub async fn assign_recording_to_piece(
+        &self,
+        recording: RecordingId,
+        piece: Option<PieceId>,
+    ) -> color_eyre::Result<RecordingInfo> {
+        let state = self.shared.state.lock().await;
+        state
+            .store
+            .assign_recording_to_piece(recording, piece)
             .await?;
         let rec = state.store.get_recording_info_by_id(recording).await?;
         self.shared.notify(StateChange::RecordUpdate {
@@ -110,10 +372,96 @@ impl App {
         Ok(rec)
     }
 
+    pub async fn add_recording_tag(
+        &self,
+        recording: RecordingId,
+        tag: &str,
+    ) -> color_eyre::Result<RecordingInfo> {
+        let state = self.shared.state.lock().await;
+        state.store.add_recording_tag(recording, tag).await?;
+        let rec = state.store.get_recording_info_by_id(recording).await?;
+        self.shared.notify(StateChange::RecordUpdate {
+            recording: rec.clone(),
+        });
+        Ok(rec)
+    }
+
+    pub async fn remove_recording_tag(
+        &self,
+        recording: RecordingId,
+        tag: &str,
+    ) -> color_eyre::Result<RecordingInfo> {
+        let state = self.shared.state.lock().await;
+        state.store.remove_recording_tag(recording, tag).await?;
+        let rec = state.store.get_recording_info_by_id(recording).await?;
+        self.shared.notify(StateChange::RecordUpdate {
+            recording: rec.clone(),
+        });
+        Ok(rec)
+    }
+
+    pub async fn query_tags(&self) -> color_eyre::Result<Vec<TagCount>> {
+        let state = self.shared.state.lock().await;
+        state.store.get_tags().await
+    }
+
+    pub async fn query_pieces(&self) -> color_eyre::Result<Vec<Piece>> {
+        let state = self.shared.state.lock().await;
+        state.store.get_pieces().await
+    }
+
+    pub async fn get_piece(&self, piece: PieceId) -> color_eyre::Result<Piece> {
+        let state = self.shared.state.lock().await;
+        state.store.get_piece_by_id(piece).await
+    }
+
+    pub async fn create_piece(
+        &self,
+        title: String,
+        composer: String,
+        catalogue_number: Option<String>,
+    ) -> color_eyre::Result<Piece> {
+        let state = self.shared.state.lock().await;
+        let piece = state
+            .store
+            .insert_piece(title, composer, catalogue_number)
+            .await?;
+        self.shared.notify(StateChange::PieceUpdate {
+            piece: piece.clone(),
+        });
+        Ok(piece)
+    }
+
+    pub async fn update_piece(
+        &self,
+        piece: PieceId,
+        title: String,
+        composer: String,
+        catalogue_number: Option<String>,
+    ) -> color_eyre::Result<Piece> {
+        let state = self.shared.state.lock().await;
+        let piece = state
+            .store
+            .update_piece(piece, title, composer, catalogue_number)
+            .await?;
+        self.shared.notify(StateChange::PieceUpdate {
+            piece: piece.clone(),
+        });
+        Ok(piece)
+    }
+
+    pub async fn delete_piece(&self, piece: PieceId) -> color_eyre::Result<()> {
+        let state = self.shared.state.lock().await;
+        state.store.delete_piece_by_id(piece).await?;
+        self.shared
+            .notify(StateChange::PieceDelete { piece_id: piece });
+        Ok(())
+    }
+
     pub async fn classify_recording(
         &self,
         recording: RecordingId,
-    ) -> color_eyre::Result<Vec<(String, f64)>> {
+    ) -> color_eyre::Result<Vec<Classification>> {
         let state = self.shared.state.lock().await;
 
         // TODO: optimize
@@ -139,22 +487,40 @@ impl App {
         let midi_data = state.store.get_recording_midi(recording).await?;
         update_histogram(&midi_data, &mut query_hist)?;
 
-        // Build histogram per name
+        // Build histogram per piece, falling back to the name for unassigned recordings
+        #[derive(PartialEq, Eq, Hash)]
+        enum Group<'a> {
+            Piece(PieceId),
+            Name(&'a str),
+        }
+
         let recs = state.store.get_recording_infos().await?;
-        let mut groups: HashMap<&str, Vec<u32>> = HashMap::new();
+        let mut groups: HashMap<Group, Vec<u32>> = HashMap::new();
 
         for rec in recs.iter() {
-            if !rec.name.is_empty() && rec.id != recording {
-                let group = groups
-                    .entry(rec.name.as_str())
-                    .or_insert_with(|| vec![0; 128]);
-
-                let midi_data = state.store.get_recording_midi(rec.id).await?;
-                update_histogram(&midi_data, group)?;
+            if rec.id == recording {
+                continue;
             }
+            let key = match rec.piece_id {
+                Some(piece) => Group::Piece(piece),
+                None if !rec.name.is_empty() => Group::Name(rec.name.as_str()),
+                None => continue,
+            };
+            let group = groups.entry(key).or_insert_with(|| vec![0; 128]);
+
+            let midi_data = state.store.get_recording_midi(rec.id).await?;
+            update_histogram(&midi_data, group)?;
         }
 
-        // Compute cosine similarity for each name
+        let titles = state
+            .store
+            .get_pieces()
+            .await?
+            .into_iter()
+            .map(|piece| (piece.id, piece.title))
+            .collect::<HashMap<_, _>>();
+
+        // Compute cosine similarity for each group
         fn cosine_sim(a: &[u32], b: &[u32]) -> f64 {
             let mag_a = a.iter().map(|x| (x * x) as f64).sum::<f64>().sqrt();
             let mag_b = b.iter().map(|x| (x * x) as f64).sum::<f64>().sqrt();
@@ -170,42 +536,135 @@ impl App {
 
         let mut outcome = groups
             .iter()
-            .filter_map(|(name, hist)| {
+            .filter_map(|(group, hist)| {
+                let (name, piece_id) = match group {
+                    Group::Piece(piece) => (titles.get(piece)?.clone(), Some(*piece)),
+                    Group::Name(name) => (name.to_string(), None),
+                };
                 Some((
-                    *name,
+                    name,
+                    piece_id,
                     ordered_float::NotNan::new(cosine_sim(&query_hist, hist)).ok()?,
                 ))
             })
             .collect::<Vec<_>>();
-        outcome.sort_by_key(|x| -x.1);
+        outcome.sort_by_key(|x| -x.2);
 
-        Ok(outcome.into_iter().map(|x| (x.0.to_owned(), x.1.into_inner())).collect())
+        Ok(outcome
+            .into_iter()
+            .map(|(name, piece_id, similarity)| Classification {
+                name,
+                piece_id,
+                similarity: similarity.into_inner(),
+            })
+            .collect())
     }
 
-    pub async fn play_recording(&self, recording: RecordingId) -> color_eyre::Result<()> {
+    pub async fn play_recording(
+        &self,
+        recording: RecordingId,
+        device: Option<&str>,
+        options: PlayOptions,
+    ) -> color_eyre::Result<()> {
+        self.shared.play_recording(recording, device, options).await
+    }
+
+    pub async fn stop_playing(&self) {
         let mut state = self.shared.state.lock().await;
-        if let Some(output) = state.listening_device.clone() {
-            info!("Playing {}", recording.0);
-            let data = state.store.get_recording_midi(recording).await?;
+        state.queue_finished = None;
+        state.player.stop().await
+    }
 
-            state
-                .player
-                .play(recording, output.id(), Box::pin(std::io::Cursor::new(data)))
-                .await?;
-            Ok(())
-        } else {
-            bail!("No device for playing song")
+    /// Continue with the next entry of the queue right away (or stop if there is none).
+    pub async fn skip_playing(&self) {
+        let mut state = self.shared.state.lock().await;
+        let previous = match state.queue_finished.take() {
+            Some((entry, _)) => Some(entry),
+            None => state.player.current().await,
+        };
+        state.player.stop().await;
+        self.shared.play_next(&mut state, previous, true).await;
+    }
+
+    pub async fn queue(&self) -> QueueStatus {
+        let state = self.shared.state.lock().await;
+        QueueStatus {
+            current: state.player.current().await,
+            entries: state.player.entries().to_vec(),
+            settings: state.player.settings().clone(),
         }
     }
 
-    pub async fn stop_playing(&self) {
+    /// Add a recording to the end of the queue, and start playing if the player is idle.
+    pub async fn enqueue_recording(&self, recording: RecordingId) -> color_eyre::Result<EntryId> {
         let mut state = self.shared.state.lock().await;
-        state.player.stop().await
+        // Fail early for recordings that don't exist or are in the trash
+        state
+            .store
+            .get_active_recording_info_by_id(recording)
+            .await?;
+        let id = state.player.enqueue(recording);
+        if state.player.status().await.is_none() && state.queue_finished.is_none() {
+            self.shared.play_next(&mut state, None, false).await;
+        }
+        Ok(id)
     }
 
-    pub async fn playing_recording(&self) -> Option<RecordingId> {
+    pub async fn remove_queue_entry(&self, id: EntryId) -> color_eyre::Result<()> {
+        let mut state = self.shared.state.lock().await;
+        if !state.player.remove(id) {
+            bail!("No queue entry {}", id.0);
+        }
+        Ok(())
+    }
+
+    /// Move an entry of the queue to the given index (or to the end).
+    pub async fn move_queue_entry(&self, id: EntryId, index: usize) -> color_eyre::Result<()> {
+        let mut state = self.shared.state.lock().await;
+        if !state.player.move_entry(id, index) {
+            bail!("No queue entry {}", id.0);
+        }
+        Ok(())
+    }
+
+    pub async fn clear_queue(&self) {
+        let mut state = self.shared.state.lock().await;
+        state.player.clear();
+    }
+
+    pub async fn set_queue_settings(&self, settings: QueueSettings) {
+        let mut state = self.shared.state.lock().await;
+        state.player.set_settings(settings);
+    }
+
+    pub async fn pause_playing(&self) -> color_eyre::Result<()> {
+        let mut state = self.shared.state.lock().await;
+        if !state.player.pause().await {
+            bail!("Nothing is playing");
+        }
+        Ok(())
+    }
+
+    pub async fn resume_playing(&self) -> color_eyre::Result<()> {
+        let mut state = self.shared.state.lock().await;
+        if !state.player.resume().await {
+            bail!("Nothing is playing");
+        }
+        Ok(())
+    }
+
+    /// Jump to the given time from the beginning of the recording that is playing.
+    pub async fn seek_playing(&self, position: Duration) -> color_eyre::Result<()> {
+        let mut state = self.shared.state.lock().await;
+        if !state.player.seek(position).await {
+            bail!("Nothing is playing");
+        }
+        Ok(())
+    }
+
+    pub async fn play_status(&self) -> Option<PlayStatus<RecordingId>> {
         let state = self.shared.state.lock().await;
-        state.player.current().await
+        state.player.status().await
     }
 }
 
@@ -226,6 +685,24 @@ async fn player_event_loop(
                     shared.notify(StateChange::PlayBegin { recording })
                 }
                 player::QueueEvent::PlaybackStop(_) => shared.notify(StateChange::PlayEnd),
+                player::QueueEvent::Paused(recording) => {
+                    shared.notify(StateChange::PlayPause { recording })
+                }
+                player::QueueEvent::Resumed(recording) => {
+                    shared.notify(StateChange::PlayResume { recording })
+                }
+                player::QueueEvent::Seeked(recording, position) => {
+                    shared.notify(StateChange::PlaySeek {
+                        recording,
+                        position,
+                    })
+                }
+                player::QueueEvent::Finished(entry, generation) => {
+                    tokio::spawn(advance_queue(shared.clone(), entry, generation));
+                }
+                player::QueueEvent::Changed(entries, settings) => {
+                    shared.notify(StateChange::QueueUpdate { entries, settings })
+                }
             },
             Err(err) => match err {
                 broadcast::error::RecvError::Closed => break,
@@ -235,88 +712,562 @@ async fn player_event_loop(
     }
 }
 
+async fn trash_purge_loop(shared: Arc<Shared>, mut shutdown_rx: broadcast::Receiver<()>) {
+    let retention = chrono::Duration::days(shared.config.trash_retention_days.into());
+    let mut interval = tokio::time::interval(TRASH_PURGE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.recv() => break,
+            _ = interval.tick() => {}
+        };
+
+        let state = shared.state.lock().await;
+        match state.store.purge_trash(Utc::now() - retention).await {
+            Ok(0) => {}
+            Ok(count) => info!("Purged {count} recordings from the trash"),
+            Err(err) => error!("Failed to purge trash: {}", err),
+        }
+    }
+}
+
+async fn backup_loop(shared: Arc<Shared>, mut shutdown_rx: broadcast::Receiver<()>) {
+    if shared.config.backup.interval_hours == 0 {
+        info!("Scheduled backups are disabled");
+        return;
+    }
+    let backup_interval = chrono::Duration::hours(shared.config.backup.interval_hours.into());
+    let directory = shared.config.backup_directory();
+    let mut interval = tokio::time::interval(BACKUP_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.recv() => break,
+            _ = interval.tick() => {}
+        };
+
+        // Look at the existing backups rather than keeping track of time, so that restarting the
+        // daemon doesn't lead to additional backups
+        let last_backup = match backup::list_backups(&directory).await {
+            Ok(backups) => backups.first().map(|backup| backup.created_at),
+            Err(err) => {
+                error!("Failed to list backups: {}", err);
+                continue;
+            }
+        };
+        if matches!(last_backup, Some(last) if Utc::now() - last < backup_interval) {
+            continue;
+        }
+
+        if let Err(err) = shared.create_backup().await {
+            error!("Failed to back up database: {}", err);
+        }
+    }
+}
+
 impl Shared {
     fn notify(&self, change: StateChange) {
         // ignore errors - we don't care if no one is listening
         let _ = self.change_tx.send(change);
     }
 
+    async fn create_backup(&self) -> color_eyre::Result<Backup> {
+        let directory = self.config.backup_directory();
+        let state = self.state.lock().await;
+        let backup = backup::create_backup(&state.store, &directory).await?;
+        drop(state);
+
+        backup::rotate_backups(&directory, &self.config.backup).await?;
+        Ok(backup)
+    }
+
     async fn handle_device_added(self: &Arc<Self>, device: Device, info: DeviceInfo) {
+        self.notify(StateChange::DeviceConnect {
+            device: device.clone(),
+            info: info.clone(),
+        });
+        self.listen(device, info).await;
+    }
+
+    async fn handle_device_removed(self: &Arc<Self>, device: Device) {
         let mut state = self.state.lock().await;
+        // The recorder finishes the song in progress and cleans up after itself
+        if let Some(stop) = state
+            .listening_devices
+            .get_mut(&device)
+            .and_then(|listener| listener.stop.take())
+        {
+            info!("Stopping recorder for {}", device.id());
+            let _ = stop.send(());
+        }
+        if state.playing_on.as_ref() == Some(&device) {
+            info!("Stopping playback on {}", device.id());
+            state.queue_finished = None;
+            state.player.stop().await;
+            state.playing_on = None;
+        }
+        drop(state);
 
-        if info.client_name.contains(&self.config.midi_device) {
-            if let Some(dev) = state.listening_device.as_ref() {
-                info!(
-                    "New devices {} ({}) matches but already recording on {}",
-                    device.id(),
-                    info.client_name,
-                    dev.id()
-                );
-            } else {
-                info!("Matching client {} connected", info.client_name);
-                // TODO: extract starting of recorder into its own function
-                match state.midi.create_recorder(&device) {
-                    Ok(rec) => {
-                        info!("Beginning recording on {}", device.id());
-                        state.listening_device = Some(device.clone());
-                        self.notify(StateChange::ListenBegin {
-                            device: device.clone(),
-                            info,
-                        });
-
-                        let inner_shared = self.clone();
-                        tokio::spawn(async move {
-                            if let Err(err) =
-                                recorder::run_recorder(inner_shared.clone(), rec).await
-                            {
-                                error!("Recorder failed: {}", err)
-                            } else {
-                                info!("Recorder shut down");
-                            }
-                            // Notify app about stopping
-                            {
-                                let mut state = inner_shared.state.lock().await;
-                                state.listening_device = None;
-                            }
-                            inner_shared.notify(StateChange::ListenEnd);
-                        });
-                    }
-                    Err(err) => {
-                        error!("Failed to set up recorder for {}: {}", device.id(), err);
-                    }
-                }
-            }
-        } else {
+        self.notify(StateChange::DeviceDisconnect { device });
+    }
+
+    /// Start recording from all connected devices that match the config but aren't recorded from
+    /// yet, e.g. because they were connected while the recorder of a previous device was still
+    /// shutting down.
+    async fn listen_to_all(self: &Arc<Self>) {
+        let devices = self.state.lock().await.devices.devices();
+        for known in devices.into_iter().filter(|known| known.connected) {
+            self.listen(known.device, known.info).await;
+        }
+    }
+
+    /// Start recording from the device if it matches the config, retrying in the background if
+    /// setting up the recorder fails.
+    async fn listen(self: &Arc<Self>, device: Device, info: DeviceInfo) {
+        if !self
+            .config
+            .matches_device(&info.client_name, &info.port_name)
+        {
             info!(
-                "Ignoring client {} ({}): no match",
+                "Ignoring device {} ({}: {}): no match",
+                device.id(),
+                info.client_name,
+                info.port_name
+            );
+            return;
+        }
+
+        let mut state = self.state.lock().await;
+        if state.listening_devices.contains_key(&device) || state.listen_retries.contains(&device) {
+            debug!(
+                "Already recording on {} ({})",
                 device.id(),
                 info.client_name
             );
+            return;
+        }
+
+        info!("Matching client {} connected", info.client_name);
+        if let Err(err) = self.start_listener(&mut state, &device, &info) {
+            error!("Failed to set up recorder for {}: {}", device.id(), err);
+            state.listen_retries.insert(device.clone());
+            tokio::spawn(retry_listener(self.clone(), device, info));
         }
     }
 
-    async fn handle_device_removed(self: &Arc<Self>, _device: Device) {}
+    fn start_listener(
+        self: &Arc<Self>,
+        state: &mut State,
+        device: &Device,
+        info: &DeviceInfo,
+    ) -> color_eyre::Result<()> {
+        let rec = state.midi.create_recorder(device)?;
+        let (stop_tx, stop_rx) = oneshot::channel();
+
+        info!("Beginning recording on {}", device.id());
+        state.listening_devices.insert(
+            device.clone(),
+            Listener {
+                info: info.clone(),
+                stop: Some(stop_tx),
+            },
+        );
+        let policy = self.config.segmentation.policy_for(&info.client_name);
+        let gestures = self.config.gestures.clone();
+        self.notify(StateChange::ListenBegin {
+            device: device.clone(),
+            info: info.clone(),
+        });
+
+        let inner_shared = self.clone();
+        let device = device.clone();
+        let info = info.clone();
+        tokio::spawn(async move {
+            let result = recorder::run_recorder(
+                inner_shared.clone(),
+                rec,
+                stop_rx,
+                device.clone(),
+                info,
+                policy,
+                gestures,
+            )
+            .await;
+            let failed = result.is_err();
+            if let Err(err) = result {
+                error!("Recorder for {} failed: {}", device.id(), err)
+            } else {
+                info!("Recorder for {} shut down", device.id());
+            }
+            // Notify app about stopping
+            {
+                let mut state = inner_shared.state.lock().await;
+                state.listening_devices.remove(&device);
+                state.recording_devices.remove(&device);
+            }
+            inner_shared.notify(StateChange::ListenEnd { device });
+            if failed {
+                // Don't restart a broken recorder in a tight loop
+                tokio::time::sleep(LISTEN_RETRY_MIN_DELAY).await;
+            }
+            inner_shared.listener_ended.notify_one();
+        });
+        Ok(())
+    }
+
+    async fn set_recording(&self, device: &Device, recording: bool) {
+        let mut state = self.state.lock().await;
+        if recording {
+            state.recording_devices.insert(device.clone());
+        } else {
+            state.recording_devices.remove(device);
+        }
+    }
 
-    pub(crate) async fn start_recording(&self) {
-        self.notify(StateChange::RecordBegin);
+    /// Returns the journal for the new recording, unless it couldn't be created.
+    pub(crate) async fn start_recording(
+        &self,
+        device: &Device,
+        info: &DeviceInfo,
+    ) -> Option<Journal> {
+        self.set_recording(device, true).await;
+        self.notify(StateChange::RecordBegin {
+            device: device.clone(),
+        });
+        match Journal::create(&self.config.journal_directory(), info).await {
+            Ok(journal) => Some(journal),
+            Err(err) => {
+                error!("Failed to create journal, recording without: {}", err);
+                None
+            }
+        }
     }
 
-    pub(crate) async fn finish_recording(&self, events: Vec<RecordEvent>) {
+    pub(crate) async fn finish_recording(
+        &self,
+        device: &Device,
+        info: &DeviceInfo,
+        events: Vec<RecordEvent>,
+        stop_reason: StopReason,
+        journal: Option<Journal>,
+    ) {
+        self.set_recording(device, false).await;
+
+        let TakeStats {
+            note_count,
+            distinct_pitches,
+            length_seconds,
+        } = TakeStats::of(&events);
+
+        // E.g. when a song consisted only of a gesture
+        if note_count == 0 {
+            info!("Discarding song without notes");
+            self.discard_recording(device, journal).await;
+            return;
+        }
+
+        let short_takes = &self.config.short_takes;
+        let short = short_takes.is_short(note_count, length_seconds, distinct_pitches);
+
+        if short && short_takes.action == ShortTakeAction::Discard {
+            info!("Discarding short take ({} notes)", note_count);
+            remove_journal(journal).await;
+            self.notify(StateChange::RecordShort {
+                device: device.clone(),
+                note_count,
+                distinct_pitches,
+                length_seconds,
+                recording: None,
+            });
+            return;
+        }
+
         let state = self.state.lock().await;
-        let data = encode_midi(events);
-        match state.store.insert_recording(data).await {
+        let result = state
+            .store
+            .insert_recording(
+                encode_midi(&events),
+                Utc::now(),
+                Some(stop_reason),
+                Some(info),
+                false,
+                short,
+            )
+            .await;
+        match result {
             Ok(recording) => {
                 info!("Recording saved with id {}", recording.id.0);
-                self.notify(StateChange::RecordEnd { recording });
+                remove_journal(journal).await;
+                if short {
+                    info!("Flagged short take ({} notes)", note_count);
+                    self.notify(StateChange::RecordShort {
+                        device: device.clone(),
+                        note_count,
+                        distinct_pitches,
+                        length_seconds,
+                        recording: Some(recording),
+                    });
+                } else {
+                    self.notify(StateChange::RecordEnd {
+                        device: device.clone(),
+                        recording,
+                    });
+                }
             }
             Err(err) => {
+                // The journal is kept, so the recording will be recovered on the next start
                 error!("Failed to store recording: {}", err);
                 self.notify(StateChange::RecordError {
+                    device: device.clone(),
                     message: err.to_string(),
                 });
             }
         }
     }
+
+    pub(crate) async fn discard_recording(&self, device: &Device, journal: Option<Journal>) {
+        self.set_recording(device, false).await;
+        remove_journal(journal).await;
+        self.notify(StateChange::RecordDiscard {
+            device: device.clone(),
+        });
+    }
+
+    /// Mark the last recording made on the device as favourite.
+    pub(crate) async fn mark_last_recording_favourite(&self, info: &DeviceInfo) {
+        let state = self.state.lock().await;
+        let result = async {
+            let recording = match last_recording(&state.store, info).await? {
+                Some(recording) => recording,
+                None => bail!("There are no recordings"),
+            };
+            state
+                .store
+                .add_recording_tag(recording.id, FAVOURITE_TAG)
+                .await?;
+            state.store.get_recording_info_by_id(recording.id).await
+        }
+        .await;
+        match result {
+            Ok(recording) => {
+                info!("Marked recording {} as favourite", recording.id.0);
+                self.notify(StateChange::RecordUpdate { recording });
+            }
+            Err(err) => error!("Failed to mark last recording as favourite: {}", err),
+        }
+    }
+
+    /// Play back the last recording made on the device, on that device.
+    pub(crate) async fn replay_last_recording(&self, device: &Device, info: &DeviceInfo) {
+        let recording = {
+            let state = self.state.lock().await;
+            last_recording(&state.store, info).await
+        };
+        let result = match recording {
+            Ok(Some(recording)) => {
+                self.play_recording(recording.id, Some(&device.id()), PlayOptions::default())
+                    .await
+            }
+            Ok(None) => Err(eyre!("There are no recordings")),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            error!("Failed to replay last recording: {}", err);
+        }
+    }
+
+    /// Play a recording on the given device (by id or client name), or by default on the device it
+    /// was recorded on.
+    async fn play_recording(
+        &self,
+        recording: RecordingId,
+        device: Option<&str>,
+        options: PlayOptions,
+    ) -> color_eyre::Result<()> {
+        if !player::TEMPO_FACTOR_RANGE.contains(&options.tempo_factor) {
+            let message = format!("Unsupported tempo factor {}", options.tempo_factor);
+            bail!(InvalidRequest(message));
+        }
+        if !player::TRANSPOSE_RANGE.contains(&options.transpose) {
+            let message = format!("Unsupported transposition {}", options.transpose);
+            bail!(InvalidRequest(message));
+        }
+        if let Some(looping) = options.looping {
+            if looping.start >= looping.end {
+                let message = "The loop needs to start before it ends".to_owned();
+                bail!(InvalidRequest(message));
+            }
+            if looping.repetitions == Some(0) {
+                let message = "The loop needs to be played at least once".to_owned();
+                bail!(InvalidRequest(message));
+            }
+        }
+
+        let mut state = self.state.lock().await;
+        let (output, playback) = create_playback(&state, recording, device, options).await?;
+        state.queue_finished = None;
+        state.player.play(recording, playback, options).await;
+        state.playing_on = Some(output);
+        state.play_options = options;
+        Ok(())
+    }
+
+    /// Play the next entry of the queue (if any) after `previous`, on the device and with the
+    /// options the player was last started with. Entries that fail to load are dropped.
+    async fn play_next(
+        &self,
+        state: &mut State,
+        mut previous: Option<QueueEntry<RecordingId>>,
+        skipped: bool,
+    ) {
+        while let Some(entry) = state.player.take_next(previous.take(), skipped) {
+            let device = state.playing_on.as_ref().map(Device::id);
+            // A loop only makes sense for the recording it was chosen for
+            let options = PlayOptions {
+                looping: None,
+                ..state.play_options
+            };
+            match create_playback(state, entry.item, device.as_deref(), options).await {
+                Ok((output, playback)) => {
+                    state.player.play_entry(entry, playback, options).await;
+                    state.playing_on = Some(output);
+                    return;
+                }
+                Err(err) => error!("Failed to play queued recording {}: {}", entry.item.0, err),
+            }
+        }
+    }
+}
+
+/// Load a recording for playing on the given device (by id or client name), or by default on the
+/// device it was recorded on.
+async fn create_playback(
+    state: &State,
+    recording: RecordingId,
+    device: Option<&str>,
+    options: PlayOptions,
+) -> color_eyre::Result<(Device, midi::Playback)> {
+    let rec = state
+        .store
+        .get_active_recording_info_by_id(recording)
+        .await?;
+    let output = match device {
+        Some(target) => state
+            .listening_devices
+            .iter()
+            .find(|(device, listener)| {
+                device.id() == target || listener.info.client_name.contains(target)
+            })
+            .map(|(device, _)| device.clone())
+            .ok_or_else(|| eyre!("Device '{}' is not connected", target))?,
+        None => {
+            let recorded_on = state.listening_devices.iter().find(|(_, listener)| {
+                rec.device_client_name.as_deref() == Some(listener.info.client_name.as_str())
+                    && rec.device_port_name.as_deref() == Some(listener.info.port_name.as_str())
+            });
+            match recorded_on.or_else(|| {
+                state
+                    .listening_devices
+                    .iter()
+                    .min_by_key(|(device, _)| *device)
+            }) {
+                Some((device, _)) => device.clone(),
+                None => bail!("No device for playing song"),
+            }
+        }
+    };
+
+    info!("Playing {} on {}", recording.0, output.id());
+    let data = state.store.get_recording_midi(recording).await?;
+    let events = options.transpose_events(import::normalize_midi(&data)?);
+    let playback = state.midi.create_playback(
+        &output,
+        events,
+        options.tempo_factor,
+        options.looping.map(PlayLoop::to_section),
+    )?;
+    Ok((output, playback))
+}
+
+/// Play the next entry of the queue once the gap after a finished entry has passed, unless the
+/// player was started or stopped in the meantime.
+async fn advance_queue(shared: Arc<Shared>, finished: QueueEntry<RecordingId>, generation: u64) {
+    let gap = {
+        let mut state = shared.state.lock().await;
+        if state.player.generation() != generation {
+            return;
+        }
+        state.queue_finished = Some((finished, generation));
+        state.player.settings().gap
+    };
+    tokio::time::sleep(gap).await;
+
+    let mut state = shared.state.lock().await;
+    let finished = match state.queue_finished.take() {
+        Some((finished, finished_generation)) if finished_generation == generation => finished,
+        other => {
+            state.queue_finished = other;
+            return;
+        }
+    };
+    shared.play_next(&mut state, Some(finished), false).await;
+}
+
+/// Keep trying to set up the recorder of a device, for as long as it is connected.
+async fn retry_listener(shared: Arc<Shared>, device: Device, info: DeviceInfo) {
+    let mut delay = LISTEN_RETRY_MIN_DELAY;
+    loop {
+        tokio::time::sleep(delay).await;
+
+        let mut state = shared.state.lock().await;
+        let connected = state
+            .devices
+            .devices()
+            .iter()
+            .any(|known| known.device == device && known.connected);
+        if !connected {
+            info!("Giving up on recorder for disconnected {}", device.id());
+            break;
+        }
+        match shared.start_listener(&mut state, &device, &info) {
+            Ok(()) => break,
+            Err(err) => {
+                delay = (delay * 2).min(LISTEN_RETRY_MAX_DELAY);
+                warn!(
+                    "Failed to set up recorder for {}, retrying in {}s: {}",
+                    device.id(),
+                    delay.as_secs(),
+                    err
+                );
+            }
+        }
+    }
+    shared.state.lock().await.listen_retries.remove(&device);
+}
+
+/// Delete the journal of a recording that was stored or discarded.
+async fn remove_journal(journal: Option<Journal>) {
+    if let Some(journal) = journal {
+        if let Err(err) = journal.remove().await {
+            error!("Failed to remove journal: {}", err);
+        }
+    }
+}
+
+/// The most recent recording made on the device that is not in the trash.
+async fn last_recording(
+    store: &RecordingStore,
+    info: &DeviceInfo,
+) -> color_eyre::Result<Option<RecordingInfo>> {
+    let page = store
+        .query_recording_infos(RecordingQuery {
+            device_client_name: Some(info.client_name.clone()),
+            device_port_name: Some(info.port_name.clone()),
+            limit: Some(1),
+            ..RecordingQuery::default()
+        })
+        .await?;
+    Ok(page.recordings.into_iter().next())
 }
 
 async fn midi_event_loop(
@@ -331,6 +1282,9 @@ async fn midi_event_loop(
                 info!("Device listener stopped");
                 break;
             }
+            _ = shared.listener_ended.notified() => {
+                shared.listen_to_all().await;
+            }
             event = listener.next() => {
                 match event? {
                     midi::DeviceEvent::Connected { device, info } => {
@@ -360,19 +1314,59 @@ pub enum StateChange {
     /// App begins listening the given MIDI device
     ListenBegin { device: Device, info: DeviceInfo },
     /// App stops listening MIDI device (usually because it was disconnected)
-    ListenEnd,
-    /// App starts recording
-    RecordBegin,
-    /// App stops recording (due to MIDI inactivity)
-    RecordEnd { recording: RecordingInfo },
+    ListenEnd { device: Device },
+    /// A MIDI device was connected (whether or not it is recorded from)
+    DeviceConnect { device: Device, info: DeviceInfo },
+    /// A MIDI device was disconnected
+    DeviceDisconnect { device: Device },
+    /// App starts recording on the device
+    RecordBegin { device: Device },
+    /// App stops recording on the device (due to MIDI inactivity)
+    RecordEnd {
+        device: Device,
+        recording: RecordingInfo,
+    },
+    /// App stops recording on the device without saving the song
+    RecordDiscard { device: Device },
+    /// App stops recording a song that seems accidental, `recording` is `None` if it was discarded
+    /// and otherwise flagged as short
+    RecordShort {
+        device: Device,
+        note_count: u32,
+        distinct_pitches: u32,
+        length_seconds: f64,
+        recording: Option<RecordingInfo>,
+    },
     /// Failed to record song
-    RecordError { message: String },
-    /// A recording was deleted
+    RecordError { device: Device, message: String },
+    /// A recording was imported from a MIDI file
+    RecordImport { recording: RecordingInfo },
+    /// A recording was deleted (i.e. moved to the trash)
     RecordDelete { recording_id: RecordingId },
+    /// A recording was restored from the trash
+    RecordRestore { recording: RecordingInfo },
     /// A recording was updated
     RecordUpdate { recording: RecordingInfo },
+    /// A piece was created or updated
+    PieceUpdate { piece: Piece },
+    /// A piece was deleted (recordings assigned to it are now unassigned)
+    PieceDelete { piece_id: PieceId },
     /// App starts playing back
     PlayBegin { recording: RecordingId },
     /// App stops playing back
     PlayEnd,
+    /// Playback was paused
+    PlayPause { recording: RecordingId },
+    /// Paused playback was resumed
+    PlayResume { recording: RecordingId },
+    /// Playback jumped to a different time of the recording
+    PlaySeek {
+        recording: RecordingId,
+        position: Duration,
+    },
+    /// The entries or the settings of the play queue changed
+    QueueUpdate {
+        entries: Vec<QueueEntry<RecordingId>>,
+        settings: QueueSettings,
+    },
 }
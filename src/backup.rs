@@ -0,0 +1,268 @@
+//! # Database backups
+//!
+//! Backups are complete copies of the database made with `VACUUM INTO`, which is safe to do while
+//! the daemon is running. They are named after the time they were made
+//! (`autorec-20220521T134100Z.db`), which is all that is needed to rotate them: only the most
+//! recent backup of each of the last few days and weeks is kept.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, SubsecRound, TimeZone, Utc};
+use color_eyre::eyre::{bail, Context};
+use tracing::{info, warn};
+
+use crate::{
+    config::BackupConfig,
+    store::{self, DataDirectoryLock, RecordingStore},
+};
+
+const FILE_NAME_PREFIX: &str = "autorec-";
+const FILE_NAME_SUFFIX: &str = ".db";
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub path: PathBuf,
+    pub created_at: DateTime<Utc>,
+    pub size: u64,
+}
+
+/// Back up the database into `directory`.
+pub async fn create_backup(store: &RecordingStore, directory: &Path) -> color_eyre::Result<Backup> {
+    tokio::fs::create_dir_all(directory)
+        .await
+        .with_context(|| format!("creating {}", directory.display()))?;
+
+    // Whole seconds only, so that the timestamp survives the round trip through the file name
+    let created_at = Utc::now().trunc_subsecs(0);
+    let path = directory.join(file_name(created_at));
+    if path.exists() {
+        bail!("Backup file '{}' already exists", path.display());
+    }
+
+    // Write to a temporary file first, so that incomplete backups are never picked up
+    let tmp_path = path.with_extension("db.tmp");
+    if tmp_path.exists() {
+        tokio::fs::remove_file(&tmp_path).await?;
+    }
+    store.backup_into(&tmp_path).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+
+    let size = tokio::fs::metadata(&path).await?.len();
+    info!("Created backup {}", path.display());
+    Ok(Backup {
+        path,
+        created_at,
+        size,
+    })
+}
+
+/// All backups in `directory`, most recent first.
+pub async fn list_backups(directory: &Path) -> color_eyre::Result<Vec<Backup>> {
+    let mut backups = Vec::new();
+    let mut entries = match tokio::fs::read_dir(directory).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(backups),
+        Err(err) => {
+            return Err(err).with_context(|| format!("reading {}", directory.display()));
+        }
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let created_at = match entry.file_name().to_str().and_then(parse_file_name) {
+            Some(created_at) => created_at,
+            None => continue,
+        };
+        backups.push(Backup {
+            path: entry.path(),
+            created_at,
+            size: entry.metadata().await?.len(),
+        });
+    }
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.created_at));
+    Ok(backups)
+}
+
+/// Delete all backups that are no longer needed according to the configured retention.
+///
+/// The most recent backup is always kept.
+pub async fn rotate_backups(directory: &Path, config: &BackupConfig) -> color_eyre::Result<()> {
+    let backups = list_backups(directory).await?;
+
+    let mut days = HashSet::new();
+    let mut weeks = HashSet::new();
+    for (index, backup) in backups.iter().enumerate() {
+        let date = backup.created_at.with_timezone(&Local).naive_local().date();
+        let iso_week = date.iso_week();
+        let week = (iso_week.year(), iso_week.week());
+
+        // Backups are sorted by time, so the first one seen for each day/week is the most recent
+        let mut keep = index == 0;
+        if days.len() < config.keep_daily as usize && days.insert(date) {
+            keep = true;
+        }
+        if weeks.len() < config.keep_weekly as usize && weeks.insert(week) {
+            keep = true;
+        }
+
+        if !keep {
+            info!("Deleting old backup {}", backup.path.display());
+            if let Err(err) = tokio::fs::remove_file(&backup.path).await {
+                warn!("Failed to delete {}: {}", backup.path.display(), err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the database in `data_directory` by the given backup.
+///
+/// The current database is backed up to `backup_directory` first. Fails while the daemon is
+/// running.
+pub async fn restore_backup(
+    data_directory: &Path,
+    backup_directory: &Path,
+    backup: &Path,
+) -> color_eyre::Result<()> {
+    // Held until the restored database is in place
+    let _lock = DataDirectoryLock::acquire(data_directory)?;
+
+    // A left-over rollback journal or write-ahead log of the old database would corrupt the
+    // restored one
+    for suffix in ["-journal", "-wal", "-shm"] {
+        let path = data_directory.join(format!("autorec.db{suffix}"));
+        if path.exists() {
+            bail!(
+                "'{}' exists, the database was not closed properly. Start the daemon once to recover it",
+                path.display()
+            );
+        }
+    }
+
+    let version = store::check_database(backup)
+        .await
+        .with_context(|| format!("checking {}", backup.display()))?;
+    info!("Restoring {} (version {})", backup.display(), version);
+
+    // Copy next to the database first, so that replacing it is atomic
+    let tmp_path = data_directory.join("autorec.db.restore");
+    tokio::fs::copy(backup, &tmp_path)
+        .await
+        .with_context(|| format!("copying {}", backup.display()))?;
+
+    let dbfile = data_directory.join("autorec.db");
+    if dbfile.exists() {
+        let store = RecordingStore::open(data_directory).await?;
+        let current = create_backup(&store, backup_directory).await;
+        store.close().await;
+        info!("Backed up current database to {}", current?.path.display());
+    }
+
+    tokio::fs::rename(&tmp_path, &dbfile).await?;
+    Ok(())
+}
+
+fn file_name(created_at: DateTime<Utc>) -> String {
+    format!(
+        "{FILE_NAME_PREFIX}{}{FILE_NAME_SUFFIX}",
+        created_at.format(TIMESTAMP_FORMAT)
+    )
+}
+
+fn parse_file_name(file_name: &str) -> Option<DateTime<Utc>> {
+    let timestamp = file_name
+        .strip_prefix(FILE_NAME_PREFIX)?
+        .strip_suffix(FILE_NAME_SUFFIX)?;
+    let naive = NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+    Some(Utc.from_utc_datetime(&naive))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Timelike;
+
+    use super::*;
+
+    /// Create empty backup files in a fresh directory, for backups made at the given local times.
+    fn create_backups(name: &str, times: &[(u32, u32)]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("autorec-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        for &(day, hour) in times {
+            let naive = chrono::NaiveDate::from_ymd_opt(2022, 5, day)
+                .and_then(|date| date.and_hms_opt(hour, 0, 0))
+                .unwrap();
+            let created_at = Local.from_local_datetime(&naive).single().unwrap();
+            std::fs::write(directory.join(file_name(created_at.into())), b"").unwrap();
+        }
+        directory
+    }
+
+    /// Days and hours of the backups that are left, most recent first.
+    async fn remaining_backups(directory: &Path) -> Vec<(u32, u32)> {
+        list_backups(directory)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|backup| {
+                let local = backup.created_at.with_timezone(&Local);
+                (local.day(), local.hour())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rotate_daily_and_weekly() {
+        // 2022-05-16 is a Monday, the first day of an ISO week
+        let directory = create_backups(
+            "rotate",
+            &[(16, 12), (16, 8), (15, 12), (14, 12), (8, 12), (1, 12)],
+        );
+        let config = BackupConfig {
+            keep_daily: 1,
+            keep_weekly: 3,
+            ..Default::default()
+        };
+        rotate_backups(&directory, &config).await.unwrap();
+        assert_eq!(
+            remaining_backups(&directory).await,
+            [(16, 12), (15, 12), (8, 12)]
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_keeps_most_recent() {
+        let directory = create_backups("rotate-none", &[(16, 8), (16, 12), (2, 12)]);
+        let config = BackupConfig {
+            keep_daily: 0,
+            keep_weekly: 0,
+            ..Default::default()
+        };
+        rotate_backups(&directory, &config).await.unwrap();
+        assert_eq!(remaining_backups(&directory).await, [(16, 12)]);
+    }
+
+    #[tokio::test]
+    async fn restore_refuses_while_locked() {
+        let directory = create_backups("restore-locked", &[]);
+        let backup = directory.join("backup.db");
+
+        let lock = DataDirectoryLock::acquire(&directory).unwrap();
+        let error = restore_backup(&directory, &directory, &backup)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("is locked"), "{}", error);
+        drop(lock);
+
+        std::fs::write(directory.join("autorec.db-wal"), b"").unwrap();
+        let error = restore_backup(&directory, &directory, &backup)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("not closed properly"),
+            "{}",
+            error
+        );
+    }
+}
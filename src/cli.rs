@@ -0,0 +1,264 @@
+//! # Offline maintenance commands
+//!
+//! These operate directly on the recordings database, without starting the daemon (and without
+//! touching any MIDI devices). Note that a running daemon won't notice changes made this way, so
+//! open web UIs need to be reloaded.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, Utc};
+use color_eyre::eyre::Context;
+use tracing::info;
+
+use crate::{
+    backup,
+    config::AppConfig,
+    export, import,
+    midi::encode_midi,
+    store::{RecordingId, RecordingInfo, RecordingQuery, RecordingStore, StopReason},
+};
+
+/// List recordings, most recent first.
+pub async fn list(
+    store: &RecordingStore,
+    name: Option<String>,
+    tags: Vec<String>,
+    include_short: bool,
+    limit: Option<u32>,
+) -> color_eyre::Result<()> {
+    let mut remaining = limit.map(|limit| limit as usize);
+    let mut query = RecordingQuery {
+        name,
+        tags,
+        include_short,
+        ..RecordingQuery::default()
+    };
+    loop {
+        let page = store.query_recording_infos(query.clone()).await?;
+        for rec in page.recordings.iter() {
+            if remaining == Some(0) {
+                return Ok(());
+            }
+            print_summary(rec);
+            remaining = remaining.map(|n| n - 1);
+        }
+        match page.next_cursor {
+            Some(cursor) => query.cursor = Some(cursor),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Print all metadata of a single recording.
+pub async fn show(store: &RecordingStore, id: RecordingId) -> color_eyre::Result<()> {
+    let rec = store.get_recording_info_by_id(id).await?;
+
+    let piece = match rec.piece_id {
+        Some(piece_id) => {
+            let piece = store.get_piece_by_id(piece_id).await?;
+            let mut description = piece.title;
+            if !piece.composer.is_empty() {
+                description = format!("{description} ({})", piece.composer);
+            }
+            if let Some(catalogue_number) = piece.catalogue_number {
+                description = format!("{description}, {catalogue_number}");
+            }
+            description
+        }
+        None => String::new(),
+    };
+
+    println!("Id:         {}", rec.id.0);
+    println!("Name:       {}", rec.name);
+    println!("Piece:      {}", piece);
+    println!("Tags:       {}", rec.tags.0.join(", "));
+    println!("Created at: {}", format_timestamp(rec.created_at));
+    println!("Length:     {}", format_length(rec.length_seconds));
+    println!("Note count: {}", rec.note_count);
+    if let Some(client_name) = rec.device_client_name.as_ref() {
+        let port_name = rec.device_port_name.as_deref().unwrap_or_default();
+        println!("Device:     {} ({})", client_name, port_name);
+    }
+    if let Some(stop_reason) = rec.stop_reason {
+        let stop_reason = match stop_reason {
+            StopReason::Idle => "idle",
+            StopReason::StuckNotes => "stuck notes",
+            StopReason::MaxLength => "maximum length",
+            StopReason::Gesture => "gesture",
+            StopReason::Disconnect => "device disconnected",
+        };
+        println!("Stopped by: {}", stop_reason);
+    }
+    if rec.recovered {
+        println!("Recovered:  yes");
+    }
+    if rec.short {
+        println!("Short take: yes");
+    }
+    if let Some(deleted_at) = rec.deleted_at {
+        println!("Deleted at: {}", format_timestamp(deleted_at));
+    }
+    if !rec.notes.is_empty() {
+        println!();
+        println!("{}", rec.notes);
+    }
+    Ok(())
+}
+
+pub async fn rename(
+    store: &RecordingStore,
+    id: RecordingId,
+    name: String,
+) -> color_eyre::Result<()> {
+    store.rename_recording_by_id(id, name).await?;
+    info!("Renamed recording {}", id.0);
+    Ok(())
+}
+
+/// Move a recording to the trash.
+pub async fn delete(store: &RecordingStore, id: RecordingId) -> color_eyre::Result<()> {
+    store.delete_recording_by_id(id).await?;
+    info!("Moved recording {} to the trash", id.0);
+    Ok(())
+}
+
+/// Export all recordings as ZIP archive to the given file.
+pub async fn export(store: &RecordingStore, output: &Path) -> color_eyre::Result<()> {
+    let file = tokio::fs::File::create(output)
+        .await
+        .with_context(|| format!("creating {}", output.display()))?;
+    export::export_library(store, tokio::io::BufWriter::new(file)).await?;
+    info!("Exported recordings to {}", output.display());
+    Ok(())
+}
+
+/// Import MIDI files as new recordings.
+///
+/// Unless given explicitly, the creation time is derived from the file name, or otherwise the
+/// modification time of the file.
+pub async fn import(
+    store: &RecordingStore,
+    files: &[PathBuf],
+    created_at: Option<DateTime<Utc>>,
+) -> color_eyre::Result<()> {
+    // Validate everything before importing anything
+    let mut recordings = Vec::with_capacity(files.len());
+    for file in files {
+        let data = tokio::fs::read(file)
+            .await
+            .with_context(|| format!("reading {}", file.display()))?;
+        let events = import::normalize_midi(&data)
+            .with_context(|| format!("importing {}", file.display()))?;
+        let created_at = match created_at {
+            Some(created_at) => created_at,
+            None => match file.to_str().and_then(import::created_at_from_file_name) {
+                Some(created_at) => created_at,
+                None => tokio::fs::metadata(file)
+                    .await?
+                    .modified()
+                    .map_or_else(|_| Utc::now(), DateTime::<Utc>::from),
+            },
+        };
+        recordings.push((file, events, created_at));
+    }
+
+    for (file, events, created_at) in recordings {
+        let rec = store
+            .insert_recording(encode_midi(&events), created_at, None, None, false, false)
+            .await?;
+        info!("Imported {} as recording {}", file.display(), rec.id.0);
+        print_summary(&rec);
+    }
+    Ok(())
+}
+
+/// Print some numbers about the library.
+pub async fn stats(store: &RecordingStore) -> color_eyre::Result<()> {
+    let stats = store.get_library_stats().await?;
+
+    println!("Recordings:       {}", stats.recording_count);
+    println!(
+        "Total length:     {}",
+        format_length(stats.total_length_seconds)
+    );
+    println!("Total notes:      {}", stats.total_note_count);
+    if let Some(first) = stats.first_recording_at {
+        println!("First recording:  {}", format_timestamp(first));
+    }
+    if let Some(last) = stats.last_recording_at {
+        println!("Last recording:   {}", format_timestamp(last));
+    }
+    println!("Pieces:           {}", stats.piece_count);
+    println!("Tags:             {}", stats.tag_count);
+    println!("In trash:         {}", stats.trashed_count);
+    Ok(())
+}
+
+/// Back up the database right away.
+pub async fn backup(store: &RecordingStore, config: &AppConfig) -> color_eyre::Result<()> {
+    let directory = config.backup_directory();
+    let backup = backup::create_backup(store, &directory).await?;
+    backup::rotate_backups(&directory, &config.backup).await?;
+    println!("{}", backup.path.display());
+    Ok(())
+}
+
+/// List the available backups, most recent first.
+pub async fn backups(config: &AppConfig) -> color_eyre::Result<()> {
+    for backup in backup::list_backups(&config.backup_directory()).await? {
+        let file_name = backup
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        println!(
+            "{}  {:>10}  {}",
+            format_timestamp(backup.created_at),
+            backup.size,
+            file_name
+        );
+    }
+    Ok(())
+}
+
+/// Replace the database by a backup, given by path or by file name within the backup directory.
+pub async fn restore(config: &AppConfig, backup: &Path) -> color_eyre::Result<()> {
+    let backup_directory = config.backup_directory();
+    let path = if backup.exists() {
+        backup.to_owned()
+    } else {
+        backup_directory.join(backup)
+    };
+    backup::restore_backup(&config.data_directory, &backup_directory, &path).await?;
+    info!("Restored {}", path.display());
+    Ok(())
+}
+
+fn print_summary(rec: &RecordingInfo) {
+    println!(
+        "{:>6}  {}  {:>8}  {:>6}  {}",
+        rec.id.0,
+        format_timestamp(rec.created_at),
+        format_length(rec.length_seconds),
+        rec.note_count,
+        rec.name
+    );
+}
+
+fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp
+        .with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+/// Format as `m:ss`, or `h:mm:ss` for lengths of an hour or more.
+fn format_length(seconds: f64) -> String {
+    let total = seconds.round() as u64;
+    let (hours, minutes, seconds) = (total / 3600, total / 60 % 60, total % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
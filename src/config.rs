@@ -1,5 +1,7 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -11,7 +13,339 @@ pub struct Config {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub data_directory: PathBuf,
-    pub midi_device: String,
+    /// Rules for selecting the devices to record from, see [`AppConfig::matches_device`]
+    #[serde(default)]
+    pub devices: Vec<DeviceRule>,
+    /// Record from all devices whose ALSA client name contains this string, unless one of the
+    /// `devices` rules applies (kept for older configs)
+    #[serde(default)]
+    pub midi_device: Option<String>,
+    /// Number of days after which deleted recordings are permanently removed from the trash
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u32,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub segmentation: SegmentationConfig,
+    /// Key combinations for controlling the recorder from the keyboard
+    #[serde(default)]
+    pub gestures: Vec<GestureConfig>,
+    #[serde(default)]
+    pub short_takes: ShortTakeConfig,
+    #[serde(default)]
+    pub playback: PlaybackConfig,
+}
+
+fn default_trash_retention_days() -> u32 {
+    30
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// Where to store backups of the database, defaults to `backups` in the data directory
+    pub directory: Option<PathBuf>,
+    /// Minimum number of hours between two scheduled backups, 0 disables scheduled backups
+    pub interval_hours: u32,
+    /// Number of days for which the most recent backup of the day is kept
+    pub keep_daily: u32,
+    /// Number of weeks for which the most recent backup of the week is kept
+    pub keep_weekly: u32,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            interval_hours: 24,
+            keep_daily: 7,
+            keep_weekly: 4,
+        }
+    }
+}
+
+/// Selects MIDI devices by their ALSA client and port names.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceRule {
+    /// Any client name matches if not given
+    #[serde(default)]
+    pub client: Option<NameMatch>,
+    /// Any port name matches if not given
+    #[serde(default)]
+    pub port: Option<NameMatch>,
+    /// Whether matching devices are ignored rather than recorded
+    #[serde(default)]
+    pub exclude: bool,
+}
+
+impl DeviceRule {
+    pub fn matches(&self, client_name: &str, port_name: &str) -> bool {
+        let matches = |pattern: &Option<NameMatch>, name| match pattern {
+            Some(pattern) => pattern.matches(name),
+            None => true,
+        };
+        matches(&self.client, client_name) && matches(&self.port, port_name)
+    }
+}
+
+/// Written as e.g. `{ contains = "Piano" }` in the config file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NameMatch {
+    Exact(String),
+    Contains(String),
+    Regex(#[serde(with = "serde_regex")] Regex),
+}
+
+impl NameMatch {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            NameMatch::Exact(exact) => name == exact,
+            NameMatch::Contains(part) => name.contains(part.as_str()),
+            NameMatch::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// (De)serializes regular expressions as strings, so that invalid ones are reported when loading
+/// the config.
+mod serde_regex {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(regex: &Regex, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(regex.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map_err(D::Error::custom)
+    }
+}
+
+/// Deserializes durations that only make sense when positive, so that zero, negative and
+/// non-finite values are reported when loading the config.
+mod positive_seconds {
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        check(f64::deserialize(deserializer)?)
+    }
+
+    pub fn deserialize_option<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<f64>, D::Error> {
+        Option::<f64>::deserialize(deserializer)?
+            .map(check)
+            .transpose()
+    }
+
+    fn check<E: Error>(seconds: f64) -> Result<f64, E> {
+        if seconds.is_finite() && seconds > 0.0 {
+            Ok(seconds)
+        } else {
+            Err(E::custom(format!(
+                "expected a positive number of seconds, got {seconds}"
+            )))
+        }
+    }
+}
+
+/// Deserializes durations that may be zero, but not negative or non-finite.
+mod non_negative_seconds {
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        let seconds = f64::deserialize(deserializer)?;
+        if seconds.is_finite() && seconds >= 0.0 {
+            Ok(seconds)
+        } else {
+            Err(D::Error::custom(format!(
+                "expected a non-negative number of seconds, got {seconds}"
+            )))
+        }
+    }
+}
+
+/// Decides when a song ends and the next one begins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SegmentationPolicy {
+    /// Seconds without any activity after which the song ends
+    #[serde(deserialize_with = "positive_seconds::deserialize")]
+    pub idle_gap_seconds: f64,
+    /// Whether holding the sustain pedal (without any keys) keeps the song going
+    pub sustain_is_activity: bool,
+    /// Seconds without any events after which the song ends even though keys still seem to be
+    /// pressed (e.g. because a "note off" got lost)
+    #[serde(deserialize_with = "positive_seconds::deserialize")]
+    pub stuck_note_timeout_seconds: f64,
+    /// Length in seconds after which the song is split, even when playing continues
+    #[serde(deserialize_with = "positive_seconds::deserialize_option")]
+    pub max_length_seconds: Option<f64>,
+}
+
+impl Default for SegmentationPolicy {
+    fn default() -> Self {
+        Self {
+            idle_gap_seconds: 5.0,
+            sustain_is_activity: true,
+            stuck_note_timeout_seconds: 30.0,
+            max_length_seconds: None,
+        }
+    }
+}
+
+/// Overrides parts of the [`SegmentationPolicy`] for a device.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SegmentationOverride {
+    #[serde(deserialize_with = "positive_seconds::deserialize_option")]
+    pub idle_gap_seconds: Option<f64>,
+    pub sustain_is_activity: Option<bool>,
+    #[serde(deserialize_with = "positive_seconds::deserialize_option")]
+    pub stuck_note_timeout_seconds: Option<f64>,
+    #[serde(deserialize_with = "positive_seconds::deserialize_option")]
+    pub max_length_seconds: Option<f64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SegmentationConfig {
+    #[serde(flatten)]
+    pub policy: SegmentationPolicy,
+    /// Overrides for devices whose client name contains the key
+    pub devices: BTreeMap<String, SegmentationOverride>,
+}
+
+impl SegmentationConfig {
+    pub fn policy_for(&self, client_name: &str) -> SegmentationPolicy {
+        let mut policy = self.policy.clone();
+        for (pattern, overrides) in self.devices.iter() {
+            if !client_name.contains(pattern.as_str()) {
+                continue;
+            }
+            if let Some(idle_gap_seconds) = overrides.idle_gap_seconds {
+                policy.idle_gap_seconds = idle_gap_seconds;
+            }
+            if let Some(sustain_is_activity) = overrides.sustain_is_activity {
+                policy.sustain_is_activity = sustain_is_activity;
+            }
+            if let Some(stuck_note_timeout_seconds) = overrides.stuck_note_timeout_seconds {
+                policy.stuck_note_timeout_seconds = stuck_note_timeout_seconds;
+            }
+            if let Some(max_length_seconds) = overrides.max_length_seconds {
+                policy.max_length_seconds = Some(max_length_seconds);
+            }
+        }
+        policy
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GestureConfig {
+    /// MIDI note numbers of the keys that have to be held down together
+    pub keys: Vec<u8>,
+    /// Whether the sustain pedal has to be tapped while holding the keys
+    #[serde(default)]
+    pub sustain: bool,
+    pub action: GestureAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GestureAction {
+    /// End the current song, so that a new one starts with the next note
+    Split,
+    /// End the current song without saving it
+    Discard,
+    /// Mark the last recording as favourite
+    Favourite,
+    /// Play back the last recording
+    Replay,
+}
+
+/// Decides what happens to accidental recordings, like a bumped key.
+///
+/// A take is short if it falls below any of the minimums.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShortTakeConfig {
+    pub min_note_count: u32,
+    pub min_length_seconds: f64,
+    /// Minimum number of different keys played
+    pub min_distinct_pitches: u32,
+    pub action: ShortTakeAction,
+}
+
+impl Default for ShortTakeConfig {
+    fn default() -> Self {
+        Self {
+            min_note_count: 3,
+            min_length_seconds: 1.0,
+            min_distinct_pitches: 2,
+            action: ShortTakeAction::Flag,
+        }
+    }
+}
+
+impl ShortTakeConfig {
+    pub fn is_short(&self, note_count: u32, length_seconds: f64, distinct_pitches: u32) -> bool {
+        note_count < self.min_note_count
+            || length_seconds < self.min_length_seconds
+            || distinct_pitches < self.min_distinct_pitches
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaybackConfig {
+    /// Pause between two entries of the play queue
+    #[serde(deserialize_with = "non_negative_seconds::deserialize")]
+    pub gap_seconds: f64,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        Self { gap_seconds: 2.0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortTakeAction {
+    /// Do not store short takes at all
+    Discard,
+    /// Store short takes, but hide them from the listing of recordings
+    Flag,
+}
+
+impl AppConfig {
+    /// Whether to record from the device with the given names.
+    ///
+    /// The first of the `devices` rules that matches decides, so more specific rules (like
+    /// exclusions) need to come first. If no rule matches, `midi_device` is used as a fallback.
+    pub fn matches_device(&self, client_name: &str, port_name: &str) -> bool {
+        let rule = self
+            .devices
+            .iter()
+            .find(|rule| rule.matches(client_name, port_name));
+        match rule {
+            Some(rule) => !rule.exclude,
+            None => matches!(&self.midi_device, Some(name) if client_name.contains(name.as_str())),
+        }
+    }
+
+    pub fn backup_directory(&self) -> PathBuf {
+        self.backup
+            .directory
+            .clone()
+            .unwrap_or_else(|| self.data_directory.join("backups"))
+    }
+
+    pub fn journal_directory(&self) -> PathBuf {
+        self.data_directory.join("journal")
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -19,3 +353,83 @@ pub struct WebConfig {
     pub port: u16,
     pub serve_frontend: Option<PathBuf>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segmentation(toml: &str) -> Result<SegmentationConfig, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    fn playback(toml: &str) -> Result<PlaybackConfig, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn segmentation_rejects_non_positive_seconds() {
+        assert!(segmentation("idle_gap_seconds = 2.5\nmax_length_seconds = 600.0").is_ok());
+        assert!(segmentation("idle_gap_seconds = 0.0").is_err());
+        assert!(segmentation("stuck_note_timeout_seconds = -1.0").is_err());
+        assert!(segmentation("max_length_seconds = nan").is_err());
+        assert!(segmentation("[devices.Piano]\nidle_gap_seconds = 1.0").is_ok());
+        assert!(segmentation("[devices.Piano]\nmax_length_seconds = 0.0").is_err());
+    }
+
+    #[test]
+    fn playback_rejects_invalid_gap() {
+        assert_eq!(playback("gap_seconds = 0.0").unwrap().gap_seconds, 0.0);
+        assert!(playback("gap_seconds = -1.0").is_err());
+        assert!(playback("gap_seconds = inf").is_err());
+    }
+
+    #[test]
+    fn first_matching_device_rule_wins() {
+        let config = toml::from_str::<AppConfig>(
+            r#"
+            data_directory = "data"
+            midi_device = "Digital Piano"
+
+            [[devices]]
+            client = { exact = "Digital Piano" }
+            port = { contains = "Thru" }
+            exclude = true
+
+            [[devices]]
+            client = { regex = "^(Digital|Stage) Piano$" }
+            "#,
+        )
+        .unwrap();
+        assert!(config.matches_device("Digital Piano", "Digital Piano MIDI 1"));
+        assert!(!config.matches_device("Digital Piano", "Digital Piano Thru"));
+        assert!(config.matches_device("Stage Piano", "Stage Piano Thru"));
+        assert!(!config.matches_device("Midi Through", "Midi Through Port-0"));
+    }
+
+    #[test]
+    fn midi_device_is_fallback_for_devices() {
+        let config = toml::from_str::<AppConfig>(
+            r#"
+            data_directory = "data"
+            midi_device = "Piano"
+
+            [[devices]]
+            port = { exact = "Piano Thru" }
+            exclude = true
+            "#,
+        )
+        .unwrap();
+        assert!(config.matches_device("Digital Piano", "MIDI 1"));
+        assert!(!config.matches_device("Digital Piano", "Piano Thru"));
+        assert!(!config.matches_device("Synth", "MIDI 1"));
+
+        let config = toml::from_str::<AppConfig>(r#"data_directory = "data""#).unwrap();
+        assert!(!config.matches_device("Digital Piano", "MIDI 1"));
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = toml::from_str::<Config>(include_str!("../autorec.toml")).unwrap();
+        assert_eq!(config.app.segmentation.policy.idle_gap_seconds, 5.0);
+    }
+}
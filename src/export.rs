@@ -0,0 +1,295 @@
+//! # Exporting the library
+//!
+//! The export is a ZIP archive containing every recording as standard MIDI file, together with a
+//! `manifest.json` describing the metadata of all recordings.
+//!
+//! The archive is written incrementally, so that at most one decompressed MIDI file is kept in
+//! memory at a time. Since MIDI files are already compressed quite well by `zstd` in the database,
+//! and don't compress much further anyway, entries are simply stored without compression. That
+//! keeps the ZIP writer simple enough to implement it here directly.
+
+use chrono::{DateTime, Datelike, Local, Timelike, Utc};
+use color_eyre::eyre::{bail, eyre};
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::store::{PieceId, RecordingId, RecordingStore, StopReason};
+
+/// Name of the metadata file in the archive.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+#[derive(Serialize)]
+struct Manifest {
+    exported_at: DateTime<Utc>,
+    pieces: Vec<ManifestPiece>,
+    recordings: Vec<ManifestRecording>,
+}
+
+#[derive(Serialize)]
+struct ManifestPiece {
+    id: PieceId,
+    title: String,
+    composer: String,
+    catalogue_number: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ManifestRecording {
+    id: RecordingId,
+    /// Path of the MIDI file within the archive
+    file: String,
+    name: String,
+    notes: String,
+    created_at: DateTime<Utc>,
+    length_seconds: f64,
+    note_count: u32,
+    piece_id: Option<PieceId>,
+    tags: Vec<String>,
+    recovered: bool,
+    stop_reason: Option<StopReason>,
+    short: bool,
+    device_client_name: Option<String>,
+    device_port_name: Option<String>,
+}
+
+/// Write all recordings of the store as ZIP archive to `out`.
+pub async fn export_library<W: AsyncWrite + Unpin>(
+    store: &RecordingStore,
+    out: W,
+) -> color_eyre::Result<()> {
+    let recordings = store.get_recording_infos().await?;
+    let pieces = store.get_pieces().await?;
+
+    let manifest = Manifest {
+        exported_at: Utc::now(),
+        pieces: pieces
+            .into_iter()
+            .map(|piece| ManifestPiece {
+                id: piece.id,
+                title: piece.title,
+                composer: piece.composer,
+                catalogue_number: piece.catalogue_number,
+            })
+            .collect(),
+        recordings: recordings
+            .into_iter()
+            .map(|rec| ManifestRecording {
+                id: rec.id,
+                // Prefix with the id, since neither names nor timestamps are unique
+                file: format!("recordings/{} {}", rec.id.0, rec.midi_file_name()),
+                name: rec.name,
+                notes: rec.notes,
+                created_at: rec.created_at,
+                length_seconds: rec.length_seconds,
+                note_count: rec.note_count,
+                piece_id: rec.piece_id,
+                tags: rec.tags.0,
+                recovered: rec.recovered,
+                stop_reason: rec.stop_reason,
+                short: rec.short,
+                device_client_name: rec.device_client_name,
+                device_port_name: rec.device_port_name,
+            })
+            .collect(),
+    };
+
+    let mut zip = ZipWriter::new(out);
+
+    let manifest_data = serde_json::to_vec_pretty(&manifest)?;
+    zip.add_file(MANIFEST_FILE_NAME, manifest.exported_at, &manifest_data)
+        .await?;
+
+    for rec in manifest.recordings.iter() {
+        debug!("Exporting recording {} as {}", rec.id.0, rec.file);
+        let midi = store.get_recording_midi(rec.id).await?;
+        zip.add_file(&rec.file, rec.created_at, &midi).await?;
+    }
+
+    zip.finish().await?;
+    Ok(())
+}
+
+/// Minimal streaming ZIP writer supporting only uncompressed entries.
+///
+/// Does not support ZIP64, i.e. archives are limited to 65535 entries and 4 GiB in size, which
+/// should be plenty for MIDI files.
+struct ZipWriter<W> {
+    out: W,
+    /// Number of bytes written so far
+    offset: u64,
+    /// Central directory headers of the files written so far
+    central_directory: Vec<u8>,
+    entries: u16,
+}
+
+/// General purpose flag indicating that file names are UTF-8 encoded.
+const ZIP_FLAG_UTF8: u16 = 1 << 11;
+
+/// Version 2.0 of the ZIP specification is enough for what we do here.
+const ZIP_VERSION: u16 = 20;
+
+impl<W: AsyncWrite + Unpin> ZipWriter<W> {
+    fn new(out: W) -> Self {
+        Self {
+            out,
+            offset: 0,
+            central_directory: Vec::new(),
+            entries: 0,
+        }
+    }
+
+    async fn add_file(
+        &mut self,
+        name: &str,
+        modified: DateTime<Utc>,
+        data: &[u8],
+    ) -> color_eyre::Result<()> {
+        if self.entries == u16::MAX {
+            bail!("Too many files for ZIP archive");
+        }
+        let offset = u32::try_from(self.offset).map_err(|_| too_large())?;
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let name_len = u16::try_from(name.len()).map_err(|_| too_large())?;
+
+        let crc = crc32fast::hash(data);
+        let (time, date) = dos_date_time(modified);
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+        header.extend_from_slice(&ZIP_FLAG_UTF8.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // stored
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes()); // compressed size
+        header.extend_from_slice(&size.to_le_bytes()); // uncompressed size
+        header.extend_from_slice(&name_len.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        header.extend_from_slice(name.as_bytes());
+
+        let cd = &mut self.central_directory;
+        cd.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        cd.extend_from_slice(&ZIP_VERSION.to_le_bytes()); // made by
+        cd.extend_from_slice(&ZIP_VERSION.to_le_bytes()); // needed to extract
+        cd.extend_from_slice(&ZIP_FLAG_UTF8.to_le_bytes());
+        cd.extend_from_slice(&0u16.to_le_bytes()); // stored
+        cd.extend_from_slice(&time.to_le_bytes());
+        cd.extend_from_slice(&date.to_le_bytes());
+        cd.extend_from_slice(&crc.to_le_bytes());
+        cd.extend_from_slice(&size.to_le_bytes());
+        cd.extend_from_slice(&size.to_le_bytes());
+        cd.extend_from_slice(&name_len.to_le_bytes());
+        cd.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        cd.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        cd.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        cd.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+        cd.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        cd.extend_from_slice(&offset.to_le_bytes());
+        cd.extend_from_slice(name.as_bytes());
+
+        self.write(&header).await?;
+        self.write(data).await?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> color_eyre::Result<()> {
+        let cd_offset = u32::try_from(self.offset).map_err(|_| too_large())?;
+        let cd_size = u32::try_from(self.central_directory.len()).map_err(|_| too_large())?;
+
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // number of this disk
+        end.extend_from_slice(&0u16.to_le_bytes()); // disk with central directory
+        end.extend_from_slice(&self.entries.to_le_bytes()); // entries on this disk
+        end.extend_from_slice(&self.entries.to_le_bytes()); // entries in total
+        end.extend_from_slice(&cd_size.to_le_bytes());
+        end.extend_from_slice(&cd_offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+
+        let central_directory = std::mem::take(&mut self.central_directory);
+        self.write(&central_directory).await?;
+        self.write(&end).await?;
+        self.out.shutdown().await?;
+        Ok(())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.out.write_all(data).await?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+fn too_large() -> color_eyre::Report {
+    eyre!("ZIP archive too large")
+}
+
+/// Convert a timestamp to the MS-DOS format used by ZIP files (local time, 2 second resolution).
+fn dos_date_time(timestamp: DateTime<Utc>) -> (u16, u16) {
+    let local = timestamp.with_timezone(&Local);
+    // DOS timestamps can't represent anything before 1980
+    let year = local.year().clamp(1980, 2107) as u16;
+    let time =
+        (local.hour() as u16) << 11 | (local.minute() as u16) << 5 | (local.second() as u16 / 2);
+    let date = (year - 1980) << 9 | (local.month() as u16) << 5 | local.day() as u16;
+    (time, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn zip_central_directory() {
+        let files: [(&str, &[u8]); 2] = [("check.txt", b"123456789"), ("empty", b"")];
+        // Well-known CRC-32 check values of the file contents
+        let crcs = [0xcbf43926, 0];
+
+        let mut archive = Vec::new();
+        let mut zip = ZipWriter::new(&mut archive);
+        for (name, data) in files {
+            zip.add_file(name, Utc::now(), data).await.unwrap();
+        }
+        zip.finish().await.unwrap();
+
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(&archive, end), 0x06054b50);
+        assert_eq!(u16_at(&archive, end + 8), 2);
+        assert_eq!(u16_at(&archive, end + 10), 2);
+        let cd_size = u32_at(&archive, end + 12) as usize;
+        let cd_offset = u32_at(&archive, end + 16) as usize;
+        assert_eq!(cd_offset + cd_size, end);
+
+        let mut cd = cd_offset;
+        for ((name, data), crc) in files.into_iter().zip(crcs) {
+            assert_eq!(u32_at(&archive, cd), 0x02014b50);
+            assert_eq!(u32_at(&archive, cd + 16), crc);
+            assert_eq!(u32_at(&archive, cd + 20) as usize, data.len());
+            assert_eq!(u32_at(&archive, cd + 24) as usize, data.len());
+            let name_len = u16_at(&archive, cd + 28) as usize;
+            assert_eq!(&archive[cd + 46..cd + 46 + name_len], name.as_bytes());
+
+            // The local header must agree with the central directory
+            let local = u32_at(&archive, cd + 42) as usize;
+            assert_eq!(u32_at(&archive, local), 0x04034b50);
+            assert_eq!(u32_at(&archive, local + 14), crc);
+            assert_eq!(u16_at(&archive, local + 26) as usize, name_len);
+            let data_offset = local + 30 + name_len;
+            assert_eq!(&archive[data_offset..data_offset + data.len()], data);
+
+            cd += 46 + name_len;
+        }
+        assert_eq!(cd, end);
+    }
+}
@@ -0,0 +1,237 @@
+//! # Importing MIDI files
+//!
+//! Recordings made elsewhere (e.g. with `arecordmidi` or the built-in recorder of the piano) can be
+//! imported into the library. Since the rest of autorec assumes the layout produced by
+//! [`encode_midi`](crate::midi::encode_midi), imported files are normalised to that: all tracks are
+//! merged into one, and the timing is converted to [`RECORDING_PPQ`] ticks at the fixed
+//! [`RECORDING_TEMPO`], taking tempo changes of the original file into account.
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use color_eyre::eyre::bail;
+
+use crate::midi::{MidiEvent, RecordEvent, RECORDING_PPQ, RECORDING_TEMPO};
+
+/// Tempo of MIDI files that don't specify one, in microseconds per quarter note.
+const DEFAULT_TEMPO: u64 = 500_000;
+
+/// Parse a standard MIDI file and convert it to the events of a single recording.
+pub fn normalize_midi(data: &[u8]) -> color_eyre::Result<Vec<RecordEvent>> {
+    let smf = midly::Smf::parse(data)?;
+
+    // Collect events with their absolute time in microseconds
+    let mut timed_events = Vec::new();
+    match smf.header.format {
+        midly::Format::Sequential => {
+            // Tracks are independent songs played one after another
+            let mut offset = 0;
+            for track in smf.tracks.iter() {
+                let tempo_map = TempoMap::new(smf.header.timing, std::slice::from_ref(track))?;
+                offset = collect_events(track, &tempo_map, offset, &mut timed_events);
+            }
+        }
+        midly::Format::SingleTrack | midly::Format::Parallel => {
+            // Tempo changes (usually in the first track) apply to all tracks
+            let tempo_map = TempoMap::new(smf.header.timing, &smf.tracks)?;
+            for track in smf.tracks.iter() {
+                collect_events(track, &tempo_map, 0, &mut timed_events);
+            }
+        }
+    }
+    // Stable sort, so simultaneous events keep their order
+    timed_events.sort_by_key(|(micros, _)| *micros);
+
+    if !timed_events
+        .iter()
+        .any(|(_, event)| matches!(event, MidiEvent::NoteOn { .. }))
+    {
+        bail!("MIDI file does not contain any notes");
+    }
+
+    Ok(timed_events
+        .into_iter()
+        .map(|(micros, payload)| RecordEvent {
+            timestamp: micros * RECORDING_PPQ as u64 / RECORDING_TEMPO as u64,
+            payload,
+        })
+        .collect())
+}
+
+/// Guess when a recording was made based on its file name.
+///
+/// Understands the naming scheme that autorec used back when it stored recordings as files
+/// (`20220521-134100.mid`), as well as a few other common timestamp formats.
+pub fn created_at_from_file_name(file_name: &str) -> Option<DateTime<Utc>> {
+    const FORMATS: &[&str] = &[
+        "%Y%m%d-%H%M%S",
+        "%Y%m%d_%H%M%S",
+        "%Y-%m-%d %H-%M-%S",
+        "%Y-%m-%d_%H-%M-%S",
+        "%Y-%m-%dT%H-%M-%S",
+    ];
+
+    let stem = std::path::Path::new(file_name).file_stem()?.to_str()?;
+    FORMATS.iter().find_map(|format| {
+        NaiveDateTime::parse_from_str(stem, format)
+            .ok()
+            .and_then(|naive| Local.from_local_datetime(&naive).latest())
+            .map(DateTime::<Utc>::from)
+    })
+}
+
+/// Append the supported events of the track to `events`, returning the end time of the track.
+fn collect_events(
+    track: &midly::Track,
+    tempo_map: &TempoMap,
+    offset: u64,
+    events: &mut Vec<(u64, MidiEvent)>,
+) -> u64 {
+    let mut tick = 0;
+    for event in track.iter() {
+        tick += event.delta.as_int() as u64;
+        if let Some(payload) = MidiEvent::from_track_event(&event.kind) {
+            events.push((offset + tempo_map.micros(tick), payload));
+        }
+    }
+    offset + tempo_map.micros(tick)
+}
+
+/// Converts MIDI ticks into microseconds.
+enum TempoMap {
+    Metrical {
+        ticks_per_beat: u64,
+        /// Sorted list of `(tick, micros, tempo)` where each tempo starts to apply
+        segments: Vec<(u64, u64, u64)>,
+    },
+    Timecode {
+        ticks_per_second: f64,
+    },
+}
+
+impl TempoMap {
+    fn new(timing: midly::Timing, tracks: &[midly::Track]) -> color_eyre::Result<Self> {
+        match timing {
+            midly::Timing::Metrical(ticks_per_beat) => {
+                let ticks_per_beat = ticks_per_beat.as_int() as u64;
+                if ticks_per_beat == 0 {
+                    bail!("MIDI file has invalid timing of 0 ticks per beat");
+                }
+
+                let mut changes = Vec::new();
+                for track in tracks {
+                    let mut tick = 0;
+                    for event in track.iter() {
+                        tick += event.delta.as_int() as u64;
+                        if let midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(tempo)) =
+                            event.kind
+                        {
+                            changes.push((tick, tempo.as_int() as u64));
+                        }
+                    }
+                }
+                changes.sort_by_key(|(tick, _)| *tick);
+
+                let mut segments = vec![(0, 0, DEFAULT_TEMPO)];
+                for (tick, tempo) in changes {
+                    let &(last_tick, last_micros, last_tempo) =
+                        segments.last().expect("starts non-empty");
+                    let micros = last_micros + (tick - last_tick) * last_tempo / ticks_per_beat;
+                    segments.push((tick, micros, tempo));
+                }
+
+                Ok(TempoMap::Metrical {
+                    ticks_per_beat,
+                    segments,
+                })
+            }
+            midly::Timing::Timecode(fps, ticks_per_frame) => {
+                if ticks_per_frame == 0 {
+                    bail!("MIDI file has invalid timing of 0 ticks per frame");
+                }
+                Ok(TempoMap::Timecode {
+                    ticks_per_second: fps.as_f32() as f64 * ticks_per_frame as f64,
+                })
+            }
+        }
+    }
+
+    fn micros(&self, tick: u64) -> u64 {
+        match self {
+            TempoMap::Metrical {
+                ticks_per_beat,
+                segments,
+            } => {
+                // The first segment starts at tick 0, so there is always one that applies
+                let index = segments.partition_point(|(start, _, _)| *start <= tick) - 1;
+                let (start, micros, tempo) = segments[index];
+                micros + (tick - start) * tempo / ticks_per_beat
+            }
+            TempoMap::Timecode { ticks_per_second } => {
+                (tick as f64 * 1_000_000.0 / ticks_per_second) as u64
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(delta: u32, kind: midly::TrackEventKind<'static>) -> midly::TrackEvent<'static> {
+        midly::TrackEvent {
+            delta: delta.into(),
+            kind,
+        }
+    }
+
+    fn note(on: bool) -> midly::TrackEventKind<'static> {
+        let message = if on {
+            midly::MidiMessage::NoteOn {
+                key: 60.into(),
+                vel: 80.into(),
+            }
+        } else {
+            midly::MidiMessage::NoteOff {
+                key: 60.into(),
+                vel: 0.into(),
+            }
+        };
+        midly::TrackEventKind::Midi {
+            channel: 0.into(),
+            message,
+        }
+    }
+
+    #[test]
+    fn tempo_change_applies_to_all_tracks() {
+        let mut smf = midly::Smf::new(midly::Header::new(
+            midly::Format::Parallel,
+            midly::Timing::Metrical(480.into()),
+        ));
+        let end = || midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack);
+        // Twice as fast after two beats at the default tempo, i.e. after one second
+        smf.tracks.push(vec![
+            event(
+                960,
+                midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(250_000.into())),
+            ),
+            event(0, end()),
+        ]);
+        smf.tracks.push(vec![
+            event(480, note(true)),
+            event(960, note(false)),
+            event(480, note(true)),
+            event(0, end()),
+        ]);
+        let mut data = Vec::new();
+        smf.write_std(&mut data).unwrap();
+
+        let events = normalize_midi(&data).unwrap();
+        let timestamps = events
+            .iter()
+            .map(|event| event.timestamp)
+            .collect::<Vec<_>>();
+        // 0.5s, 1.25s and 1.5s at two recording beats per second
+        let ppq = u64::from(RECORDING_PPQ);
+        assert_eq!(timestamps, [ppq, 5 * ppq / 2, 3 * ppq]);
+    }
+}
@@ -0,0 +1,509 @@
+//! # Journal of recordings in progress
+//!
+//! While a song is being recorded, its events are appended to a journal file as they come in.
+//! Once the recording has been stored in the database, the journal is deleted again. Any journal
+//! that is still around when the daemon starts belongs to a recording that was interrupted (by a
+//! crash or a power cut), and is turned into a regular recording flagged as recovered.
+//!
+//! A journal starts with [`MAGIC`] and the ALSA client and port names of the device that is being
+//! recorded, each as length (`u16`) and UTF-8 bytes. Then follows one record per event: the
+//! timestamp in ticks (`u64`), the length of the MIDI message (`u16`), all little endian, and the
+//! message itself. A record that was only partially written when the daemon died is ignored.
+//! Journals written by older versions have no device names ([`MAGIC_V2`]), and `u32` timestamps
+//! on top of that ([`MAGIC_V1`]).
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{bail, Context};
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{ShortTakeAction, ShortTakeConfig},
+    midi::{encode_midi, DeviceInfo, MidiEvent, RecordEvent, TakeStats},
+    store::{RecordingInfo, RecordingStore},
+};
+
+const MAGIC: &[u8; 8] = b"ARJRNL03";
+const MAGIC_V2: &[u8; 8] = b"ARJRNL02";
+const MAGIC_V1: &[u8; 8] = b"ARJRNL01";
+const FILE_EXTENSION: &str = "journal";
+
+/// How often the journal is synced to disk. Writes reach the OS right away, so this only matters
+/// for power cuts.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: tokio::fs::File,
+    /// Everything before the first record
+    header: Vec<u8>,
+    last_sync: Instant,
+}
+
+impl Journal {
+    /// Start a new journal in `directory`, for a recording made on `device`.
+    pub async fn create(directory: &Path, device: &DeviceInfo) -> color_eyre::Result<Self> {
+        tokio::fs::create_dir_all(directory)
+            .await
+            .with_context(|| format!("creating {}", directory.display()))?;
+
+        let file_name = format!(
+            "{}.{FILE_EXTENSION}",
+            Utc::now().format("%Y%m%dT%H%M%S%.6fZ")
+        );
+        let path = directory.join(file_name);
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("creating {}", path.display()))?;
+        let mut header = MAGIC.to_vec();
+        for name in [&device.client_name, &device.port_name] {
+            // Names aren't anywhere near as long, but they must not spill into the records
+            let name = &name.as_bytes()[..name.len().min(u16::MAX.into())];
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(name);
+        }
+        file.write_all(&header).await?;
+        file.flush().await?;
+
+        Ok(Self {
+            path,
+            file,
+            header,
+            last_sync: Instant::now(),
+        })
+    }
+
+    pub async fn append(&mut self, event: &RecordEvent) -> color_eyre::Result<()> {
+        let record = match encode_record(event) {
+            Some(record) => record,
+            None => {
+                warn!("Not journaling oversized event");
+                return Ok(());
+            }
+        };
+
+        self.file.write_all(&record).await?;
+        // The file buffers writes internally, make sure they reach the OS
+        self.file.flush().await?;
+
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.file.sync_data().await?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Replace the journaled events, e.g. after removing a gesture from the end of the recording.
+    ///
+    /// The new journal is written next to the old one first, so that one of them is always intact.
+    pub async fn rewrite(&mut self, events: &[RecordEvent]) -> color_eyre::Result<()> {
+        let mut data = self.header.clone();
+        for record in events.iter().filter_map(encode_record) {
+            data.extend_from_slice(&record);
+        }
+
+        let tmp_path = self.path.with_extension(format!("{FILE_EXTENSION}.tmp"));
+        let mut file = tokio::fs::File::create(&tmp_path)
+            .await
+            .with_context(|| format!("creating {}", tmp_path.display()))?;
+        file.write_all(&data).await?;
+        file.sync_data().await?;
+        drop(file);
+        tokio::fs::rename(&tmp_path, &self.path)
+            .await
+            .with_context(|| format!("replacing {}", self.path.display()))?;
+
+        self.file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("opening {}", self.path.display()))?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Delete the journal, once the recording has been stored.
+    pub async fn remove(self) -> color_eyre::Result<()> {
+        drop(self.file);
+        tokio::fs::remove_file(&self.path)
+            .await
+            .with_context(|| format!("removing {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Encode an event as journal record, `None` if its message is too large.
+fn encode_record(event: &RecordEvent) -> Option<Vec<u8>> {
+    let data = event.payload.to_bytes();
+    let len = u16::try_from(data.len()).ok()?;
+
+    let mut record = Vec::with_capacity(10 + data.len());
+    record.extend_from_slice(&event.timestamp.to_le_bytes());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&data);
+    Some(record)
+}
+
+/// Store the recordings of all journals left behind in `directory`, and delete the journals.
+///
+/// Short takes are handled like those that were recorded completely.
+pub async fn recover_recordings(
+    store: &RecordingStore,
+    directory: &Path,
+    short_takes: &ShortTakeConfig,
+) -> color_eyre::Result<Vec<RecordingInfo>> {
+    let mut recovered = Vec::new();
+    let mut entries = match tokio::fs::read_dir(directory).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(recovered),
+        Err(err) => {
+            return Err(err).with_context(|| format!("reading {}", directory.display()));
+        }
+    };
+
+    let mut paths = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension() == Some(OsStr::new(FILE_EXTENSION)) {
+            paths.push(path);
+        }
+    }
+    // Oldest first, so that ids are assigned in the order the recordings were made
+    paths.sort();
+
+    for path in paths {
+        let contents = match read_journal(&path).await {
+            Ok(contents) => contents,
+            Err(err) => {
+                // Leave it alone, maybe it can be recovered manually
+                error!("Failed to read journal {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        let JournalContents {
+            events,
+            device,
+            last_modified,
+        } = contents;
+
+        let stats = TakeStats::of(&events);
+        let short = short_takes.is_short(
+            stats.note_count,
+            stats.length_seconds,
+            stats.distinct_pitches,
+        );
+        if stats.note_count == 0 {
+            info!("Removing journal without notes {}", path.display());
+        } else if short && short_takes.action == ShortTakeAction::Discard {
+            info!(
+                "Discarding short take ({} notes) from {}",
+                stats.note_count,
+                path.display()
+            );
+        } else {
+            let result = store
+                .insert_recording(
+                    encode_midi(&events),
+                    last_modified,
+                    None,
+                    device.as_ref(),
+                    true,
+                    short,
+                )
+                .await;
+            match result {
+                Ok(rec) => {
+                    info!("Recovered recording {} from {}", rec.id.0, path.display());
+                    if short {
+                        info!("Flagged short take ({} notes)", stats.note_count);
+                    }
+                    recovered.push(rec);
+                }
+                Err(err) => {
+                    // Keep the journal, so that recovery is attempted again on the next start
+                    error!(
+                        "Failed to recover recording from {}: {}",
+                        path.display(),
+                        err
+                    );
+                    continue;
+                }
+            }
+        }
+        if let Err(err) = tokio::fs::remove_file(&path).await {
+            error!("Failed to remove journal {}: {}", path.display(), err);
+        }
+    }
+    Ok(recovered)
+}
+
+/// What is left of a recording in its journal.
+struct JournalContents {
+    events: Vec<RecordEvent>,
+    /// Not known for journals written by older versions
+    device: Option<DeviceInfo>,
+    /// When the journal was last written to
+    last_modified: DateTime<Utc>,
+}
+
+async fn read_journal(path: &Path) -> color_eyre::Result<JournalContents> {
+    let data = tokio::fs::read(path).await?;
+    let last_modified = tokio::fs::metadata(path)
+        .await?
+        .modified()
+        .map_or_else(|_| Utc::now(), DateTime::<Utc>::from);
+
+    let mut device = None;
+    let (mut rest, timestamp_len) = if let Some(rest) = data.strip_prefix(MAGIC.as_slice()) {
+        match read_device(rest) {
+            Some((info, rest)) => {
+                device = Some(info);
+                (rest, 8)
+            }
+            // The daemon died while writing the header
+            None => (&[][..], 8),
+        }
+    } else if let Some(rest) = data.strip_prefix(MAGIC_V2.as_slice()) {
+        (rest, 8)
+    } else if let Some(rest) = data.strip_prefix(MAGIC_V1.as_slice()) {
+        (rest, 4)
+    } else if MAGIC.starts_with(&data) {
+        // The daemon might have died before even writing the header
+        (&[][..], 8)
+    } else {
+        bail!("Not a journal file");
+    };
+
+    let mut events = Vec::new();
+    let header_len = timestamp_len + 2;
+    while rest.len() >= header_len {
+        let mut timestamp = [0; 8];
+        timestamp[..timestamp_len].copy_from_slice(&rest[..timestamp_len]);
+        let timestamp = u64::from_le_bytes(timestamp);
+        let len = u16::from_le_bytes(rest[timestamp_len..header_len].try_into().expect("2 bytes"))
+            as usize;
+        let data = match rest.get(header_len..header_len + len) {
+            Some(data) => data,
+            None => break,
+        };
+        rest = &rest[header_len + len..];
+
+        match MidiEvent::from_bytes(data) {
+            Some(payload) => events.push(RecordEvent { timestamp, payload }),
+            None => warn!("Skipping invalid event in {}", path.display()),
+        }
+    }
+    if !rest.is_empty() {
+        warn!("Ignoring incomplete event at the end of {}", path.display());
+    }
+    Ok(JournalContents {
+        events,
+        device,
+        last_modified,
+    })
+}
+
+/// Read the device names from the start of a journal, `None` if they are incomplete.
+fn read_device(data: &[u8]) -> Option<(DeviceInfo, &[u8])> {
+    fn read_name(data: &[u8]) -> Option<(String, &[u8])> {
+        let len = u16::from_le_bytes(data.get(..2)?.try_into().expect("2 bytes")) as usize;
+        let name = data.get(2..2 + len)?;
+        Some((String::from_utf8_lossy(name).into_owned(), &data[2 + len..]))
+    }
+
+    let (client_name, rest) = read_name(data)?;
+    let (port_name, rest) = read_name(rest)?;
+    let info = DeviceInfo {
+        client_name,
+        port_name,
+    };
+    Some((info, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory of its own.
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("autorec-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn note_on(timestamp: u64, note: u8) -> RecordEvent {
+        RecordEvent {
+            timestamp,
+            payload: MidiEvent::NoteOn {
+                channel: 0,
+                note,
+                velocity: 64,
+            },
+        }
+    }
+
+    fn device() -> DeviceInfo {
+        DeviceInfo {
+            client_name: "Digital Piano".to_owned(),
+            port_name: "Digital Piano MIDI 1".to_owned(),
+        }
+    }
+
+    /// Timestamps and notes of the events, for comparing them.
+    fn notes(events: &[RecordEvent]) -> Vec<(u64, u8)> {
+        events
+            .iter()
+            .map(|event| match event.payload {
+                MidiEvent::NoteOn { note, .. } => (event.timestamp, note),
+                ref payload => panic!("unexpected event {:?}", payload),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rewrite_replaces_events() {
+        let directory = test_directory("journal-rewrite");
+        let mut journal = Journal::create(&directory, &device()).await.unwrap();
+        for event in [note_on(0, 60), note_on(10, 21), note_on(11, 22)] {
+            journal.append(&event).await.unwrap();
+        }
+        journal.rewrite(&[note_on(0, 60)]).await.unwrap();
+        journal.append(&note_on(20, 62)).await.unwrap();
+
+        let contents = read_journal(&journal.path).await.unwrap();
+        assert_eq!(notes(&contents.events), [(0, 60), (20, 62)]);
+        let device = contents.device.unwrap();
+        assert_eq!(device.client_name, "Digital Piano");
+        assert_eq!(device.port_name, "Digital Piano MIDI 1");
+        let files = std::fs::read_dir(&directory).unwrap().count();
+        assert_eq!(files, 1, "temporary file should be gone");
+    }
+
+    #[tokio::test]
+    async fn recover_short_takes() {
+        let directory = test_directory("journal-recover");
+        let journal_directory = directory.join("journal");
+        let store = RecordingStore::open(&directory).await.unwrap();
+        let song = [note_on(0, 60), note_on(1000, 62), note_on(2000, 64)];
+        for events in [&song[..], &song[..1]] {
+            let mut journal = Journal::create(&journal_directory, &device())
+                .await
+                .unwrap();
+            for event in events {
+                journal.append(event).await.unwrap();
+            }
+        }
+
+        let recovered = recover_recordings(&store, &journal_directory, &Default::default())
+            .await
+            .unwrap();
+        let flags = recovered
+            .iter()
+            .map(|rec| (rec.recovered, rec.short))
+            .collect::<Vec<_>>();
+        assert_eq!(flags, [(true, false), (true, true)]);
+        let client_name = recovered[0].device_client_name.as_deref();
+        assert_eq!(client_name, Some("Digital Piano"));
+
+        let mut journal = Journal::create(&journal_directory, &device())
+            .await
+            .unwrap();
+        journal.append(&song[0]).await.unwrap();
+        let short_takes = ShortTakeConfig {
+            action: ShortTakeAction::Discard,
+            ..Default::default()
+        };
+        let recovered = recover_recordings(&store, &journal_directory, &short_takes)
+            .await
+            .unwrap();
+        assert!(recovered.is_empty());
+        let journals = std::fs::read_dir(&journal_directory).unwrap().count();
+        assert_eq!(journals, 0);
+    }
+
+    /// Read a journal file with the given contents.
+    async fn read_data(name: &str, data: &[u8]) -> color_eyre::Result<Vec<(u64, u8)>> {
+        let path = test_directory(name).join(format!("test.{FILE_EXTENSION}"));
+        std::fs::write(&path, data).unwrap();
+        let contents = read_journal(&path).await?;
+        Ok(notes(&contents.events))
+    }
+
+    /// Start of a journal in the current version, with the given device names.
+    fn header(client_name: &str, port_name: &str) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        for name in [client_name, port_name] {
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn read_current_version() {
+        let mut data = header("Piano", "");
+        for event in [note_on(0, 60), note_on(1 << 40, 62)] {
+            data.extend_from_slice(&encode_record(&event).unwrap());
+        }
+        let events = read_data("journal-v3", &data).await.unwrap();
+        assert_eq!(events, [(0, 60), (1 << 40, 62)]);
+    }
+
+    #[tokio::test]
+    async fn read_version_2() {
+        let mut data = MAGIC_V2.to_vec();
+        data.extend_from_slice(&encode_record(&note_on(1 << 40, 60)).unwrap());
+        let events = read_data("journal-v2", &data).await.unwrap();
+        assert_eq!(events, [(1 << 40, 60)]);
+    }
+
+    #[tokio::test]
+    async fn read_version_1() {
+        let message = note_on(0, 60).payload.to_bytes();
+        let mut data = MAGIC_V1.to_vec();
+        data.extend_from_slice(&1000u32.to_le_bytes());
+        data.extend_from_slice(&(message.len() as u16).to_le_bytes());
+        data.extend_from_slice(&message);
+        let events = read_data("journal-v1", &data).await.unwrap();
+        assert_eq!(events, [(1000, 60)]);
+    }
+
+    #[tokio::test]
+    async fn read_truncated_header() {
+        assert!(read_data("journal-empty", b"").await.unwrap().is_empty());
+        assert!(read_data("journal-header", &MAGIC[..4])
+            .await
+            .unwrap()
+            .is_empty());
+        let device = &header("Piano", "MIDI 1")[..12];
+        assert!(read_data("journal-device", device)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(read_data("journal-invalid", b"MThd").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_truncated_record() {
+        let record = encode_record(&note_on(5, 60)).unwrap();
+        let mut data = header("Piano", "MIDI 1");
+        data.extend_from_slice(&record);
+        // Only the timestamp, and the timestamp and length without the complete message
+        for partial in [&record[..8], &record[..record.len() - 1]] {
+            let mut data = data.clone();
+            data.extend_from_slice(partial);
+            let events = read_data("journal-truncated", &data).await.unwrap();
+            assert_eq!(events, [(5, 60)]);
+        }
+    }
+}
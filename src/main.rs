@@ -4,7 +4,8 @@ use axum::{
     routing::{delete, get, get_service, post, put},
     Extension, Router,
 };
-use clap::Parser;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use color_eyre::{eyre::Context, Result};
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -16,13 +17,20 @@ use tower_http::{
 use tracing::{error, info};
 
 mod app;
+mod backup;
+mod cli;
 mod config;
+mod export;
+mod import;
+mod journal;
 mod midi;
 mod player;
 mod recorder;
 mod server;
 mod store;
 
+use store::RecordingId;
+
 /// Program to automatically start MIDI recordings of songs played on an attached MIDI device.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -30,17 +38,107 @@ pub struct Args {
     /// Path of the config file
     #[clap(short('c'), long, default_value("autorec.toml"))]
     pub config: PathBuf,
+
+    /// What to do, defaults to running the recording daemon
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the daemon for recording songs and serving the web UI
+    Run,
+    /// List recordings, most recent first
+    List {
+        /// Only list recordings whose name contains this string
+        #[clap(long)]
+        name: Option<String>,
+        /// Only list recordings having this tag (can be given multiple times)
+        #[clap(long)]
+        tag: Vec<String>,
+        /// Also list recordings that were flagged as short
+        #[clap(long)]
+        short: bool,
+        /// Maximum number of recordings to list
+        #[clap(short('n'), long)]
+        limit: Option<u32>,
+    },
+    /// Show all metadata of a recording
+    Show { id: i32 },
+    /// Rename a recording
+    Rename { id: i32, name: String },
+    /// Move a recording to the trash
+    Delete { id: i32 },
+    /// Export all recordings as ZIP archive of MIDI files, with a manifest containing the metadata
+    Export {
+        /// Path of the ZIP file to create
+        output: PathBuf,
+    },
+    /// Import MIDI files as new recordings
+    Import {
+        /// MIDI files to import
+        #[clap(required(true))]
+        files: Vec<PathBuf>,
+        /// Creation time of the recordings (RFC 3339), derived from the files if not given
+        #[clap(long)]
+        created_at: Option<DateTime<Utc>>,
+    },
+    /// Print statistics about the library
+    Stats,
+    /// Back up the database
+    Backup,
+    /// List the available backups of the database
+    Backups,
+    /// Replace the database by a backup (the daemon must not be running)
+    Restore {
+        /// Path or file name of the backup to restore
+        backup: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    // Log to stderr, so that the output of commands can be used in scripts
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
     color_eyre::install()?;
 
     let args = Args::parse();
     let config_toml = std::fs::read_to_string(args.config).context("reading config file")?;
     let config = toml::from_str::<config::Config>(&config_toml).context("parsing config file")?;
 
+    let command = match args.command.unwrap_or(Command::Run) {
+        Command::Run => return run_daemon(config).await,
+        Command::Backups => return cli::backups(&config.app).await,
+        Command::Restore { backup } => return cli::restore(&config.app, &backup).await,
+        command => command,
+    };
+
+    let store = store::RecordingStore::open(&config.app.data_directory).await?;
+    let result = match command {
+        Command::Run | Command::Backups | Command::Restore { .. } => {
+            unreachable!("handled above")
+        }
+        Command::List {
+            name,
+            tag,
+            short,
+            limit,
+        } => cli::list(&store, name, tag, short, limit).await,
+        Command::Show { id } => cli::show(&store, RecordingId(id)).await,
+        Command::Rename { id, name } => cli::rename(&store, RecordingId(id), name).await,
+        Command::Delete { id } => cli::delete(&store, RecordingId(id)).await,
+        Command::Export { output } => cli::export(&store, &output).await,
+        Command::Import { files, created_at } => cli::import(&store, &files, created_at).await,
+        Command::Stats => cli::stats(&store).await,
+        Command::Backup => cli::backup(&store, &config.app).await,
+    };
+    store.close().await;
+    result
+}
+
+async fn run_daemon(config: config::Config) -> Result<()> {
     // Initialize state
     let app = app::App::new(config.app).await?;
 
@@ -52,17 +150,62 @@ async fn main() -> Result<()> {
         let app = app.clone();
         async move {
             let mut router = Router::new()
-                //.route("/devices", get(server::devices))
+                .route("/devices", get(server::devices))
                 .route("/recordings", get(server::get_recordings))
+                .route("/recordings/search", get(server::search_recordings))
+                .route("/recordings/import", post(server::import_recordings))
                 .route(
                     "/recordings/:recording_id",
                     delete(server::delete_recording),
                 )
                 .route("/recordings/:recording_id", put(server::update_recording))
+                .route(
+                    "/recordings/:recording_id/midi",
+                    get(server::get_recording_midi),
+                )
                 .route("/recordings/:recording_id/classify", post(server::classify_recording))
+                .route(
+                    "/recordings/:recording_id/restore",
+                    post(server::restore_recording),
+                )
+                .route("/trash", get(server::get_trash))
+                .route("/export.zip", get(server::export_zip))
+                .route("/admin/backup", post(server::create_backup))
+                .route(
+                    "/recordings/:recording_id/piece",
+                    put(server::assign_recording_piece),
+                )
+                .route(
+                    "/recordings/:recording_id/tags/:tag",
+                    put(server::add_recording_tag).delete(server::remove_recording_tag),
+                )
+                .route("/tags", get(server::get_tags))
+                .route(
+                    "/pieces",
+                    get(server::get_pieces).post(server::create_piece),
+                )
+                .route(
+                    "/pieces/:piece_id",
+                    get(server::get_piece)
+                        .put(server::update_piece)
+                        .delete(server::delete_piece),
+                )
                 .route("/play", post(server::play))
                 .route("/stop", post(server::stop))
+                .route("/pause", post(server::pause))
+                .route("/resume", post(server::resume))
+                .route("/seek", post(server::seek))
                 .route("/play-status", get(server::play_status))
+                .route(
+                    "/queue",
+                    get(server::get_queue)
+                        .post(server::enqueue)
+                        .delete(server::clear_queue),
+                )
+                .route("/queue/skip", post(server::skip))
+                .route("/queue/settings", put(server::update_queue_settings))
+                .route("/queue/:entry_id", delete(server::remove_queue_entry))
+                .route("/queue/:entry_id/position", put(server::move_queue_entry))
                 .route("/updates-sse", get(server::updates_sse));
 
             if let Some(dir) = config.web.serve_frontend.as_ref() {
@@ -1,10 +1,16 @@
 // NOTE: Only supports Linux (via ALSA) at the moment
 
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
 use alsa::seq::Addr;
+use chrono::{DateTime, Utc};
 
 mod alsa_backend;
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct Device {
     client_id: i32,
     port_id: i32,
@@ -31,6 +37,53 @@ pub struct DeviceInfo {
     pub port_name: String,
 }
 
+/// A MIDI port that was seen at some point while the daemon was running.
+#[derive(Debug, Clone)]
+pub struct KnownDevice {
+    pub device: Device,
+    pub info: DeviceInfo,
+    pub connected: bool,
+    /// When the device was last connected or disconnected
+    pub changed_at: DateTime<Utc>,
+}
+
+/// Keeps track of all MIDI ports seen by the [`DeviceListener`]. Cloning is cheap, all clones share
+/// the same data.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceRegistry {
+    // std Mutex since we're only protecting data
+    devices: Arc<Mutex<BTreeMap<Device, KnownDevice>>>,
+}
+
+impl DeviceRegistry {
+    /// All devices seen so far, ordered by id.
+    pub fn devices(&self) -> Vec<KnownDevice> {
+        let devices = self.devices.lock().expect("not poisoned");
+        devices.values().cloned().collect()
+    }
+
+    fn connected(&self, device: &Device, info: &DeviceInfo) {
+        let mut devices = self.devices.lock().expect("not poisoned");
+        devices.insert(
+            device.clone(),
+            KnownDevice {
+                device: device.clone(),
+                info: info.clone(),
+                connected: true,
+                changed_at: Utc::now(),
+            },
+        );
+    }
+
+    fn disconnected(&self, device: &Device) {
+        let mut devices = self.devices.lock().expect("not poisoned");
+        if let Some(known) = devices.get_mut(device) {
+            known.connected = false;
+            known.changed_at = Utc::now();
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum DeviceEvent {
     Connected { device: Device, info: DeviceInfo },
@@ -39,11 +92,12 @@ pub enum DeviceEvent {
 
 #[derive(Debug, Clone)]
 pub struct RecordEvent {
-    pub timestamp: u32,
+    /// Ticks at [`RECORDING_PPQ`] and [`RECORDING_BPM`], never going backwards
+    pub timestamp: u64,
     pub payload: MidiEvent,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MidiEvent {
     NoteOn {
         channel: u8,
@@ -59,7 +113,187 @@ pub enum MidiEvent {
         controller: u32,
         value: i32,
     },
-    // TODO: do we need more?
+    /// Pressure on a single key ("polyphonic aftertouch")
+    KeyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    /// Pressure on all keys of the channel ("channel aftertouch")
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    PitchBend {
+        channel: u8,
+        /// Between -8192 and 8191, 0 being the center
+        value: i16,
+    },
+    /// System exclusive message, without the leading `0xF0` but including the trailing `0xF7`
+    SysEx {
+        data: Vec<u8>,
+    },
+}
+
+impl MidiEvent {
+    /// Convert to an event of a standard MIDI file.
+    pub fn to_track_event(&self) -> midly::TrackEventKind<'_> {
+        let (channel, message) = match *self {
+            MidiEvent::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (
+                channel,
+                midly::MidiMessage::NoteOn {
+                    key: note.into(),
+                    vel: velocity.into(),
+                },
+            ),
+            MidiEvent::NoteOff { channel, note } => (
+                channel,
+                midly::MidiMessage::NoteOff {
+                    key: note.into(),
+                    vel: 0.into(),
+                },
+            ),
+            MidiEvent::ControlChange {
+                channel,
+                controller,
+                value,
+            } => (
+                channel,
+                midly::MidiMessage::Controller {
+                    controller: (controller as u8).into(),
+                    value: (value as u8).into(),
+                },
+            ),
+            MidiEvent::KeyPressure {
+                channel,
+                note,
+                pressure,
+            } => (
+                channel,
+                midly::MidiMessage::Aftertouch {
+                    key: note.into(),
+                    vel: pressure.into(),
+                },
+            ),
+            MidiEvent::ProgramChange { channel, program } => (
+                channel,
+                midly::MidiMessage::ProgramChange {
+                    program: program.into(),
+                },
+            ),
+            MidiEvent::ChannelPressure { channel, pressure } => (
+                channel,
+                midly::MidiMessage::ChannelAftertouch {
+                    vel: pressure.into(),
+                },
+            ),
+            MidiEvent::PitchBend { channel, value } => (
+                channel,
+                midly::MidiMessage::PitchBend {
+                    bend: midly::PitchBend::from_int(value.clamp(-8192, 8191)),
+                },
+            ),
+            MidiEvent::SysEx { ref data } => return midly::TrackEventKind::SysEx(data),
+        };
+        midly::TrackEventKind::Midi {
+            channel: channel.into(),
+            message,
+        }
+    }
+
+    /// Convert from an event of a standard MIDI file, `None` if the event is not supported.
+    pub fn from_track_event(kind: &midly::TrackEventKind) -> Option<Self> {
+        match *kind {
+            midly::TrackEventKind::Midi { channel, message } => {
+                Some(Self::from_message(channel.as_int(), message))
+            }
+            // Only complete messages, continuations are stored as "escape" events
+            midly::TrackEventKind::SysEx(data) => Some(MidiEvent::SysEx {
+                data: data.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Encode the event as it would be sent over the wire.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self.to_track_event() {
+            midly::TrackEventKind::Midi { channel, message } => {
+                midly::live::LiveEvent::Midi { channel, message }
+                    .write_std(&mut bytes)
+                    .expect("writing to vec doesn't fail");
+            }
+            midly::TrackEventKind::SysEx(data) => {
+                bytes.push(0xF0);
+                bytes.extend_from_slice(data);
+            }
+            _ => unreachable!("only MIDI and SysEx events are created"),
+        }
+        bytes
+    }
+
+    /// Decode an event as it was sent over the wire, `None` if the event is not supported.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0xF0, data @ ..] => Some(MidiEvent::SysEx {
+                data: data.to_vec(),
+            }),
+            _ => match midly::live::LiveEvent::parse(bytes).ok()? {
+                midly::live::LiveEvent::Midi { channel, message } => {
+                    Some(Self::from_message(channel.as_int(), message))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    fn from_message(channel: u8, message: midly::MidiMessage) -> Self {
+        match message {
+            midly::MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => MidiEvent::NoteOn {
+                channel,
+                note: key.as_int(),
+                velocity: vel.as_int(),
+            },
+            // A "note on" with zero velocity is a "note off"
+            midly::MidiMessage::NoteOn { key, .. } | midly::MidiMessage::NoteOff { key, .. } => {
+                MidiEvent::NoteOff {
+                    channel,
+                    note: key.as_int(),
+                }
+            }
+            midly::MidiMessage::Controller { controller, value } => MidiEvent::ControlChange {
+                channel,
+                controller: controller.as_int().into(),
+                value: value.as_int().into(),
+            },
+            midly::MidiMessage::Aftertouch { key, vel } => MidiEvent::KeyPressure {
+                channel,
+                note: key.as_int(),
+                pressure: vel.as_int(),
+            },
+            midly::MidiMessage::ProgramChange { program } => MidiEvent::ProgramChange {
+                channel,
+                program: program.as_int(),
+            },
+            midly::MidiMessage::ChannelAftertouch { vel } => MidiEvent::ChannelPressure {
+                channel,
+                pressure: vel.as_int(),
+            },
+            midly::MidiMessage::PitchBend { bend } => MidiEvent::PitchBend {
+                channel,
+                value: bend.as_int(),
+            },
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -87,10 +321,34 @@ impl Manager {
             },
         )
     }
+
+    /// Set up playing `events` to the device, see [`Playback::schedule`] for actually playing them.
+    ///
+    /// A `tempo_factor` other than 1 plays faster or slower than recorded. With a `section`, only
+    /// that part of the events is played, repeatedly.
+    pub fn create_playback(
+        &self,
+        dest: &Device,
+        events: Vec<RecordEvent>,
+        tempo_factor: f64,
+        section: Option<LoopSection>,
+    ) -> color_eyre::Result<Playback> {
+        alsa_backend::MidiPlayback::new(
+            &self.registry,
+            Addr {
+                client: dest.client_id,
+                port: dest.port_id,
+            },
+            events,
+            tempo_factor,
+            section,
+        )
+    }
 }
 
 pub type DeviceListener = alsa_backend::DeviceListener;
 pub type Recorder = alsa_backend::MidiRecorder;
+pub type Playback = alsa_backend::MidiPlayback;
 
 /// pulses per quarter note of our recordings
 pub const RECORDING_PPQ: u16 = 96;
@@ -104,7 +362,166 @@ pub const RECORDING_BPM: u16 = 120;
 /// Microseconds per quarter note
 pub const RECORDING_TEMPO: u32 = 1_000_000 * 60 / (RECORDING_BPM as u32);
 
-pub fn encode_midi(events: Vec<RecordEvent>) -> midly::Smf<'static> {
+/// Convert ticks of a recording into seconds.
+pub fn ticks_to_seconds(ticks: u64) -> f64 {
+    ticks as f64 * RECORDING_TEMPO as f64 / (RECORDING_PPQ as f64 * 1_000_000.0)
+}
+
+/// Convert seconds into ticks of a recording.
+pub fn seconds_to_ticks(seconds: f64) -> u64 {
+    (seconds * RECORDING_PPQ as f64 * 1_000_000.0 / RECORDING_TEMPO as f64) as u64
+}
+
+/// What is needed to tell accidental short takes apart from actual songs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TakeStats {
+    pub note_count: u32,
+    /// Number of different keys played
+    pub distinct_pitches: u32,
+    pub length_seconds: f64,
+}
+
+impl TakeStats {
+    pub fn of(events: &[RecordEvent]) -> Self {
+        let pitches = events
+            .iter()
+            .filter_map(|event| match event.payload {
+                MidiEvent::NoteOn { note, .. } => Some(note),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        Self {
+            note_count: pitches.len() as u32,
+            distinct_pitches: pitches.iter().collect::<BTreeSet<_>>().len() as u32,
+            length_seconds: events
+                .last()
+                .map_or(0.0, |event| ticks_to_seconds(event.timestamp)),
+        }
+    }
+}
+
+/// Controller numbers of the sustain, sostenuto and soft pedals.
+pub const PEDAL_CONTROLLERS: [u32; 3] = [64, 66, 67];
+
+/// Events for putting all controllers into the state they are in right before the given tick, e.g.
+/// when playback jumps there. Pedals that are only used later on are released.
+pub fn controller_events_at(events: &[RecordEvent], tick: u64) -> Vec<MidiEvent> {
+    let mut controllers = BTreeMap::new();
+    for event in events.iter() {
+        if let MidiEvent::ControlChange {
+            channel,
+            controller,
+            value,
+        } = event.payload
+        {
+            if event.timestamp < tick {
+                controllers.insert((channel, controller), value);
+            } else if PEDAL_CONTROLLERS.contains(&controller) {
+                controllers.entry((channel, controller)).or_insert(0);
+            }
+        }
+    }
+
+    controllers
+        .into_iter()
+        .map(|((channel, controller), value)| MidiEvent::ControlChange {
+            channel,
+            controller,
+            value,
+        })
+        .collect()
+}
+
+/// A section of a recording to be played repeatedly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopSection {
+    /// First tick of the section
+    pub start: u64,
+    /// Tick after the section, where playback jumps back to the start
+    pub end: u64,
+    /// How often the section is played, endlessly if not given
+    pub repetitions: Option<u32>,
+}
+
+impl LoopSection {
+    /// Events for releasing what is still held at the end of the section: notes that are sounding
+    /// (no matter when they were started), and pedals that are down.
+    pub fn release_events(&self, events: &[RecordEvent]) -> Vec<MidiEvent> {
+        let mut notes = BTreeSet::new();
+        let mut pedals = BTreeSet::new();
+        for event in events.iter().take_while(|event| event.timestamp < self.end) {
+            match event.payload {
+                MidiEvent::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                } if velocity > 0 => {
+                    notes.insert((channel, note));
+                }
+                MidiEvent::NoteOn { channel, note, .. } | MidiEvent::NoteOff { channel, note } => {
+                    notes.remove(&(channel, note));
+                }
+                MidiEvent::ControlChange {
+                    channel,
+                    controller,
+                    value,
+                } if PEDAL_CONTROLLERS.contains(&controller) => {
+                    if value > 0 {
+                        pedals.insert((channel, controller));
+                    } else {
+                        pedals.remove(&(channel, controller));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let notes = notes
+            .into_iter()
+            .map(|(channel, note)| MidiEvent::NoteOff { channel, note });
+        let pedals = pedals
+            .into_iter()
+            .map(|(channel, controller)| MidiEvent::ControlChange {
+                channel,
+                controller,
+                value: 0,
+            });
+        notes.chain(pedals).collect()
+    }
+
+    /// Events for putting the pedals into the state they are in at the start of the section. This
+    /// covers all pedals used up to the end of the section, those not used before the start are
+    /// released.
+    pub fn pedal_events(&self, events: &[RecordEvent]) -> Vec<MidiEvent> {
+        let mut pedals = BTreeMap::new();
+        for event in events.iter().take_while(|event| event.timestamp < self.end) {
+            if let MidiEvent::ControlChange {
+                channel,
+                controller,
+                value,
+            } = event.payload
+            {
+                if PEDAL_CONTROLLERS.contains(&controller) {
+                    let state = pedals.entry((channel, controller)).or_insert(0);
+                    if event.timestamp < self.start {
+                        *state = value;
+                    }
+                }
+            }
+        }
+
+        pedals
+            .into_iter()
+            .map(|((channel, controller), value)| MidiEvent::ControlChange {
+                channel,
+                controller,
+                value,
+            })
+            .collect()
+    }
+}
+
+pub fn encode_midi(events: &[RecordEvent]) -> midly::Smf<'_> {
     let mut smf = midly::Smf::new(midly::Header::new(
         midly::Format::SingleTrack,
         midly::Timing::Metrical(midly::num::u15::new(RECORDING_PPQ)),
@@ -117,42 +534,22 @@ pub fn encode_midi(events: Vec<RecordEvent>) -> midly::Smf<'static> {
     let mut last_time = events.first().map_or(0, |rev| rev.timestamp);
 
     for event in events.iter() {
-        let delta = event.timestamp - last_time;
-        last_time = event.timestamp;
+        let mut delta = event.timestamp.saturating_sub(last_time);
+        last_time = last_time.max(event.timestamp);
+
+        // Deltas are limited to 28 bits, bridge longer gaps with empty text events
+        let max_delta = midly::num::u28::max_value().as_int() as u64;
+        while delta > max_delta {
+            track.push(midly::TrackEvent {
+                delta: midly::num::u28::max_value(),
+                kind: midly::TrackEventKind::Meta(midly::MetaMessage::Text(b"")),
+            });
+            delta -= max_delta;
+        }
 
         track.push(midly::TrackEvent {
-            delta: midly::num::u28::new(delta),
-            kind: match event.payload {
-                MidiEvent::NoteOn {
-                    channel,
-                    note,
-                    velocity,
-                } => midly::TrackEventKind::Midi {
-                    channel: channel.into(),
-                    message: midly::MidiMessage::NoteOn {
-                        key: note.into(),
-                        vel: velocity.into(),
-                    },
-                },
-                MidiEvent::NoteOff { channel, note } => midly::TrackEventKind::Midi {
-                    channel: channel.into(),
-                    message: midly::MidiMessage::NoteOff {
-                        key: note.into(),
-                        vel: 0.into(),
-                    },
-                },
-                MidiEvent::ControlChange {
-                    channel,
-                    controller,
-                    value,
-                } => midly::TrackEventKind::Midi {
-                    channel: channel.into(),
-                    message: midly::MidiMessage::Controller {
-                        controller: (controller as u8).into(),
-                        value: (value as u8).into(),
-                    },
-                },
-            },
+            delta: midly::num::u28::new(delta as u32),
+            kind: event.payload.to_track_event(),
         })
     }
     track.push(midly::TrackEvent {
@@ -163,3 +560,157 @@ pub fn encode_midi(events: Vec<RecordEvent>) -> midly::Smf<'static> {
 
     smf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control_change(timestamp: u64, controller: u32, value: i32) -> RecordEvent {
+        RecordEvent {
+            timestamp,
+            payload: MidiEvent::ControlChange {
+                channel: 0,
+                controller,
+                value,
+            },
+        }
+    }
+
+    #[test]
+    fn controllers_at_tick() {
+        let events = [
+            control_change(0, 7, 100),
+            control_change(10, 64, 127),
+            control_change(20, 7, 80),
+            control_change(30, 64, 0),
+            control_change(40, 66, 127),
+        ];
+        assert_eq!(
+            controller_events_at(&events, 25),
+            [
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 7,
+                    value: 80,
+                },
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 64,
+                    value: 127,
+                },
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 66,
+                    value: 0,
+                },
+            ]
+        );
+        assert_eq!(
+            controller_events_at(&events, 0),
+            [
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 64,
+                    value: 0,
+                },
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 66,
+                    value: 0,
+                },
+            ]
+        );
+    }
+
+    fn note(timestamp: u64, note: u8, velocity: u8) -> RecordEvent {
+        RecordEvent {
+            timestamp,
+            payload: MidiEvent::NoteOn {
+                channel: 0,
+                note,
+                velocity,
+            },
+        }
+    }
+
+    #[test]
+    fn loop_section_release_events() {
+        let events = [
+            note(0, 60, 64),
+            note(2, 61, 64),
+            control_change(5, 64, 127),
+            note(10, 62, 64),
+            note(15, 61, 0),
+            note(20, 64, 64),
+            note(25, 62, 0),
+            control_change(30, 67, 127),
+            note(40, 64, 0),
+            note(45, 60, 0),
+        ];
+        let section = LoopSection {
+            start: 10,
+            end: 35,
+            repetitions: None,
+        };
+        // Also the note that was started before the section
+        assert_eq!(
+            section.release_events(&events),
+            [
+                MidiEvent::NoteOff {
+                    channel: 0,
+                    note: 60,
+                },
+                MidiEvent::NoteOff {
+                    channel: 0,
+                    note: 64,
+                },
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 64,
+                    value: 0,
+                },
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 67,
+                    value: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn loop_section_pedal_events() {
+        let events = [
+            control_change(5, 64, 127),
+            control_change(10, 66, 127),
+            control_change(20, 66, 0),
+            control_change(30, 67, 127),
+            control_change(50, 64, 0),
+        ];
+        let section = LoopSection {
+            start: 15,
+            end: 40,
+            repetitions: Some(2),
+        };
+        assert_eq!(
+            section.pedal_events(&events),
+            [
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 64,
+                    value: 127,
+                },
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 66,
+                    value: 127,
+                },
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 67,
+                    value: 0,
+                },
+            ]
+        );
+    }
+}
@@ -7,16 +7,20 @@ use std::{
 
 use alsa::{
     seq::{
-        Addr, EvCtrl, EvNote, EventType, PortCap, PortInfo, PortSubscribe, PortType, QueueTempo,
+        Addr, EvCtrl, EvNote, Event, EventType, PortCap, PortInfo, PortSubscribe, PortType,
+        QueueTempo,
     },
     Direction,
 };
+use color_eyre::eyre::bail;
 use tokio::io::unix::AsyncFd;
 use tracing::{debug, trace, warn};
 
-use crate::midi::{RECORDING_PPQ, RECORDING_BPM};
+use crate::midi::{RECORDING_BPM, RECORDING_PPQ, RECORDING_TEMPO};
 
-use super::{DeviceEvent, MidiEvent, RecordEvent};
+use super::{
+    controller_events_at, DeviceEvent, DeviceRegistry, LoopSection, MidiEvent, RecordEvent,
+};
 
 /// There should only be one instance of this.
 #[derive(Debug, Clone)]
@@ -150,6 +154,7 @@ enum AlsaDeviceEvent {
 pub struct DeviceListener {
     poll: EventsPoll<AlsaDeviceEvent>,
     active: HashSet<Addr>,
+    devices: DeviceRegistry,
 }
 
 impl DeviceListener {
@@ -190,9 +195,15 @@ impl DeviceListener {
         Ok(Self {
             poll,
             active: HashSet::new(),
+            devices: DeviceRegistry::default(),
         })
     }
 
+    /// Registry of all devices seen by this listener, kept up to date by [`Self::next`].
+    pub fn registry(&self) -> DeviceRegistry {
+        self.devices.clone()
+    }
+
     pub async fn next(&mut self) -> color_eyre::Result<DeviceEvent> {
         loop {
             let alsa_event = self
@@ -222,19 +233,18 @@ impl DeviceListener {
                         if !self.active.insert(addr) {
                             warn!("duplicate PortConnected for {:?}", addr)
                         }
-                        return Ok(DeviceEvent::Connected {
-                            device: addr.into(),
-                            info,
-                        });
+                        let device = addr.into();
+                        self.devices.connected(&device, &info);
+                        return Ok(DeviceEvent::Connected { device, info });
                     } else {
                         continue;
                     }
                 }
                 AlsaDeviceEvent::PortDisconnected { addr } => {
                     if self.active.remove(&addr) {
-                        return Ok(DeviceEvent::Disconnected {
-                            device: addr.into(),
-                        });
+                        let device = addr.into();
+                        self.devices.disconnected(&device);
+                        return Ok(DeviceEvent::Disconnected { device });
                     } else {
                         // Skip event - since we also didn't send the corresponding connect event
                         // apparently
@@ -250,6 +260,30 @@ pub struct MidiRecorder {
     poll: Option<EventsPoll<Option<RecordEvent>>>,
     bpm: u32,
     ppq: i32,
+    /// Start of a system exclusive message that ALSA delivered in several chunks
+    sysex_buffer: Vec<u8>,
+    ticks: TickCounter,
+}
+
+/// Extends the 32 bit ticks of an ALSA queue to 64 bits, so that they never wrap around.
+///
+/// At the recording tempo, the queue wraps around after about eight months. Events are timestamped
+/// by the queue as they arrive, so their ticks only ever go backwards when that happens.
+#[derive(Debug, Default)]
+struct TickCounter {
+    last_tick: u32,
+    wraps: u64,
+}
+
+impl TickCounter {
+    fn extend(&mut self, tick: u32) -> u64 {
+        if tick < self.last_tick {
+            self.wraps += 1;
+            debug!("ALSA queue tick wrapped around");
+        }
+        self.last_tick = tick;
+        (self.wraps << 32) | tick as u64
+    }
 }
 
 impl MidiRecorder {
@@ -318,20 +352,26 @@ impl MidiRecorder {
             poll: Some(poll),
             bpm: RECORDING_BPM.into(),
             ppq: RECORDING_PPQ.into(),
+            sysex_buffer: Vec::new(),
+            ticks: TickCounter::default(),
         })
     }
 
-    pub fn tick_to_duration(&self, tick: u32) -> std::time::Duration {
-        std::time::Duration::from_micros(
-            (tick as u64) * 1000000 * 60 / (self.bpm as u64 * self.ppq as u64),
-        )
+    pub fn tick_to_duration(&self, tick: u64) -> std::time::Duration {
+        std::time::Duration::from_micros(tick * 1000000 * 60 / (self.bpm as u64 * self.ppq as u64))
+    }
+
+    pub fn duration_to_tick(&self, duration: std::time::Duration) -> u64 {
+        (duration.as_micros() * (self.bpm as u128 * self.ppq as u128) / (1000000 * 60)) as u64
     }
 
     pub async fn next(&mut self) -> color_eyre::Result<Option<RecordEvent>> {
         if let Some(poll) = self.poll.as_mut() {
+            let sysex_buffer = &mut self.sysex_buffer;
+            let ticks = &mut self.ticks;
             let alsa_event = poll
                 .next(|event| {
-                    let tick = event.get_tick().expect("should have tick");
+                    let tick = ticks.extend(event.get_tick().expect("should have tick"));
 
                     let payload = match event.get_type() {
                         EventType::Noteon => {
@@ -368,6 +408,57 @@ impl MidiRecorder {
                                 value: ctrl.value,
                             })
                         }
+                        EventType::Keypress => {
+                            let note = event.get_data::<EvNote>().expect("must have note data");
+                            Some(MidiEvent::KeyPressure {
+                                channel: note.channel,
+                                note: note.note,
+                                pressure: note.velocity,
+                            })
+                        }
+                        EventType::Pgmchange => {
+                            let ctrl = event
+                                .get_data::<EvCtrl>()
+                                .expect("must have controller data");
+                            Some(MidiEvent::ProgramChange {
+                                channel: ctrl.channel,
+                                program: ctrl.value as u8,
+                            })
+                        }
+                        EventType::Chanpress => {
+                            let ctrl = event
+                                .get_data::<EvCtrl>()
+                                .expect("must have controller data");
+                            Some(MidiEvent::ChannelPressure {
+                                channel: ctrl.channel,
+                                pressure: ctrl.value as u8,
+                            })
+                        }
+                        EventType::Pitchbend => {
+                            let ctrl = event
+                                .get_data::<EvCtrl>()
+                                .expect("must have controller data");
+                            // ALSA already centers the value around zero
+                            Some(MidiEvent::PitchBend {
+                                channel: ctrl.channel,
+                                value: ctrl.value.clamp(-8192, 8191) as i16,
+                            })
+                        }
+                        EventType::Sysex => {
+                            // NOTE: Long messages are split into several events, only the first
+                            // one starting with 0xF0 and only the last one ending with 0xF7
+                            let data = event.get_ext().unwrap_or_default();
+                            if data.first() == Some(&0xF0) {
+                                sysex_buffer.clear();
+                            }
+                            sysex_buffer.extend_from_slice(data);
+                            if sysex_buffer.last() == Some(&0xF7) {
+                                let message = std::mem::take(sysex_buffer);
+                                MidiEvent::from_bytes(&message)
+                            } else {
+                                None
+                            }
+                        }
                         EventType::PortUnsubscribed => {
                             // No need to check which port as we only subscribed to one
                             return Some(None);
@@ -376,7 +467,7 @@ impl MidiRecorder {
                     };
                     payload.map(|payload| {
                         Some(RecordEvent {
-                            timestamp: tick, // TODO: handle tick overflow?
+                            timestamp: tick,
                             payload,
                         })
                     })
@@ -454,3 +545,447 @@ mod internal {
         }
     }
 }
+
+/// Plays events to a device by scheduling them on a sequencer queue.
+///
+/// Events are handed to the sequencer shortly before they are due (see [`Self::schedule`]) rather
+/// than all at once, so that long recordings don't overflow the kernel's event pool.
+///
+/// When looping, the repetitions of the section follow each other on the queue, so that the queue
+/// time keeps running and the jumps back are as precise as any other event.
+pub struct MidiPlayback {
+    client: Client,
+    port: i32,
+    queue: i32,
+    events: Vec<RecordEvent>,
+    /// Index of the next event to hand to the sequencer
+    next: usize,
+    looping: Option<Looping>,
+    /// Events to hand to the sequencer before the next one of `events`, with their queue ticks
+    pending: VecDeque<(u64, MidiEvent)>,
+    started: bool,
+    paused: bool,
+}
+
+/// Playback of a [`LoopSection`]. The queue starts at the beginning of the section, which is
+/// followed by each repetition in turn.
+struct Looping {
+    section: LoopSection,
+    /// Index of the first event of the section
+    first: usize,
+    /// Index of the first event after the section
+    last: usize,
+    /// Repetition whose events are being handed to the sequencer, counting from 0
+    repetition: u32,
+    /// Sent at the end of each repetition, see [`LoopSection::release_events`]
+    release: Vec<MidiEvent>,
+    /// Sent at the start of each repetition, see [`LoopSection::pedal_events`]
+    pedals: Vec<MidiEvent>,
+}
+
+impl Looping {
+    fn len(&self) -> u64 {
+        self.section.end - self.section.start
+    }
+
+    /// Queue tick of an event tick within the given repetition.
+    fn queue_tick(&self, repetition: u32, tick: u64) -> u64 {
+        u64::from(repetition) * self.len() + tick - self.section.start
+    }
+
+    /// Event tick of a queue tick, the end of the section once all repetitions were played.
+    fn event_tick(&self, queue_tick: u64) -> u64 {
+        match self.section.repetitions {
+            Some(repetitions) if queue_tick >= u64::from(repetitions) * self.len() => {
+                self.section.end
+            }
+            _ => self.section.start + queue_tick % self.len(),
+        }
+    }
+
+    /// Whether all repetitions have been handed to the sequencer.
+    fn is_done(&self) -> bool {
+        matches!(self.section.repetitions, Some(repetitions) if self.repetition >= repetitions)
+    }
+}
+
+impl MidiPlayback {
+    pub fn new(
+        registry: &MidiRegistry,
+        dest: Addr,
+        events: Vec<RecordEvent>,
+        tempo_factor: f64,
+        section: Option<LoopSection>,
+    ) -> color_eyre::Result<Self> {
+        let mut next = 0;
+        let mut pending = VecDeque::new();
+        let looping = match section {
+            Some(section) => {
+                let first = events.partition_point(|event| event.timestamp < section.start);
+                let last = events.partition_point(|event| event.timestamp < section.end);
+                if first >= last || section.repetitions == Some(0) {
+                    bail!("Nothing to play in the loop section");
+                }
+                let pedals = section.pedal_events(&events);
+                pending.extend(pedals.iter().map(|payload| (0, payload.clone())));
+                next = first;
+                Some(Looping {
+                    section,
+                    first,
+                    last,
+                    repetition: 0,
+                    release: section.release_events(&events),
+                    pedals,
+                })
+            }
+            None => None,
+        };
+
+        let client = registry.new_client("autorec-player")?;
+
+        // Same timing as the recordings, so that event timestamps can be used as they are. Only
+        // the tempo is scaled for playing faster or slower.
+        let queue = client.seq.alloc_queue()?;
+        let tempo = QueueTempo::empty()?;
+        tempo.set_ppq(RECORDING_PPQ as i32);
+        tempo.set_tempo((RECORDING_TEMPO as f64 / tempo_factor).round() as u32);
+        client.seq.set_queue_tempo(queue, &tempo)?;
+
+        debug!(client = client.id, "configured queue {}", queue);
+
+        // Create local port for sending events
+        let port = client.seq.create_simple_port(
+            &CString::new("MIDI playback")?,
+            PortCap::READ | PortCap::SUBS_READ,
+            PortType::MIDI_GENERIC | PortType::APPLICATION,
+        )?;
+
+        let subscribe = PortSubscribe::empty()?;
+        subscribe.set_sender(Addr {
+            client: client.id,
+            port,
+        });
+        subscribe.set_dest(dest);
+        client.seq.subscribe_port(&subscribe)?;
+
+        debug!(
+            client = client.id,
+            "subscribed {}:{} to port {}", dest.client, dest.port, port
+        );
+
+        Ok(Self {
+            client,
+            port,
+            queue,
+            events,
+            next,
+            looping,
+            pending,
+            started: false,
+            paused: false,
+        })
+    }
+
+    /// Hand all events due within `ahead` ticks of the current position to the sequencer, starting
+    /// playback on the first call. Needs to be called regularly until [`Self::is_finished`].
+    pub fn schedule(&mut self, ahead: u64) -> color_eyre::Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+        let horizon = self.queue_tick()? + ahead;
+        while self.refill() {
+            let (tick, payload) = self.pending.front().expect("pending events were refilled");
+            if *tick > horizon {
+                break;
+            }
+            let mut alsa_event = to_alsa_event(payload);
+            alsa_event.set_source(self.port);
+            alsa_event.set_subs();
+            alsa_event.schedule_tick(self.queue, false, clamp_tick(*tick));
+            match self.client.seq.event_output(&mut alsa_event) {
+                Ok(_) => {
+                    self.pending.pop_front();
+                }
+                // The sequencer is busy, the remaining events are handed over next time
+                Err(err) if err.errno() == alsa::nix::errno::Errno::EAGAIN => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.drain_output()?;
+
+        if !self.started {
+            debug!(client = self.client.id, self.queue, "starting queue");
+            self.client
+                .seq
+                .control_queue(self.queue, EventType::Start, 0, None)?;
+            self.drain_output()?;
+            self.started = true;
+        }
+        Ok(())
+    }
+
+    /// Current position in ticks of the events.
+    pub fn position(&self) -> color_eyre::Result<u64> {
+        let tick = self.queue_tick()?;
+        Ok(match self.looping.as_ref() {
+            Some(looping) => looping.event_tick(tick),
+            None => tick,
+        })
+    }
+
+    /// Whether all events have been played.
+    pub fn is_finished(&self) -> color_eyre::Result<bool> {
+        if !self.pending.is_empty() {
+            return Ok(false);
+        }
+        let last_tick = match self.looping.as_ref() {
+            Some(looping) if looping.is_done() => {
+                looping.queue_tick(looping.repetition, looping.section.start)
+            }
+            Some(_) => return Ok(false),
+            None if self.next < self.events.len() => return Ok(false),
+            None => self.events.last().map_or(0, |event| event.timestamp),
+        };
+        Ok(self.queue_tick()? >= clamp_tick(last_tick).into())
+    }
+
+    fn queue_tick(&self) -> color_eyre::Result<u64> {
+        let status = self.client.seq.get_queue_status(self.queue)?;
+        Ok(status.get_tick_time().into())
+    }
+
+    /// Make sure that the next event to hand to the sequencer is pending, returns `false` once all
+    /// events have been handed over.
+    fn refill(&mut self) -> bool {
+        if !self.pending.is_empty() {
+            return true;
+        }
+        let looping = match self.looping.as_mut() {
+            Some(looping) => looping,
+            None => {
+                if let Some(event) = self.events.get(self.next) {
+                    self.pending
+                        .push_back((event.timestamp, event.payload.clone()));
+                    self.next += 1;
+                }
+                return !self.pending.is_empty();
+            }
+        };
+
+        if self.next < looping.last {
+            let event = &self.events[self.next];
+            let tick = looping.queue_tick(looping.repetition, event.timestamp);
+            self.pending.push_back((tick, event.payload.clone()));
+            self.next += 1;
+            return true;
+        }
+        if looping.is_done() {
+            return false;
+        }
+
+        // End of a repetition: release everything, and set up the next repetition (if any)
+        looping.repetition += 1;
+        let tick = looping.queue_tick(looping.repetition, looping.section.start);
+        self.pending.extend(
+            looping
+                .release
+                .iter()
+                .map(|payload| (tick, payload.clone())),
+        );
+        if !looping.is_done() {
+            self.pending
+                .extend(looping.pedals.iter().map(|payload| (tick, payload.clone())));
+            self.next = looping.first;
+        }
+        self.refill()
+    }
+
+    /// Hold playback, releasing all notes. Events already handed to the sequencer are kept.
+    pub fn pause(&mut self) -> color_eyre::Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+        self.paused = true;
+        if self.started {
+            self.client
+                .seq
+                .control_queue(self.queue, EventType::Stop, 0, None)?;
+            self.silence(false)?;
+        }
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> color_eyre::Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        self.paused = false;
+        // Otherwise, the next call to `schedule` starts the queue
+        if self.started {
+            self.client
+                .seq
+                .control_queue(self.queue, EventType::Continue, 0, None)?;
+            self.drain_output()?;
+        }
+        Ok(())
+    }
+
+    /// Continue playing (or stay paused) at the given tick. When looping, the position is kept
+    /// within the section, and the repetition currently playing is continued.
+    pub fn seek(&mut self, tick: u64) -> color_eyre::Result<()> {
+        let current = self.queue_tick()?;
+        self.client
+            .seq
+            .control_queue(self.queue, EventType::Stop, 0, None)?;
+        // Events scheduled for the old position are no longer valid
+        self.client.seq.drop_output()?;
+        self.silence(false)?;
+
+        self.pending.clear();
+
+        let (tick, queue_tick) = match self.looping.as_mut() {
+            Some(looping) => {
+                let tick = tick.clamp(looping.section.start, looping.section.end - 1);
+                let mut repetition = (current / looping.len()).min(u32::MAX.into()) as u32;
+                if let Some(repetitions) = looping.section.repetitions {
+                    repetition = repetition.min(repetitions - 1);
+                }
+                looping.repetition = repetition;
+                (tick, looping.queue_tick(repetition, tick))
+            }
+            None => (tick, tick),
+        };
+        self.next = self.events.partition_point(|event| event.timestamp < tick);
+        // Pedals, volume etc. as they would be when playing up to the new position
+        self.pending.extend(
+            controller_events_at(&self.events, tick)
+                .into_iter()
+                .map(|payload| (queue_tick, payload)),
+        );
+        self.client.seq.control_queue(
+            self.queue,
+            EventType::SetposTick,
+            clamp_tick(queue_tick) as i32,
+            None,
+        )?;
+        // Starting would rewind the queue, from now on it only gets continued
+        self.started = true;
+        if !self.paused {
+            self.client
+                .seq
+                .control_queue(self.queue, EventType::Continue, 0, None)?;
+        }
+        self.drain_output()?;
+        Ok(())
+    }
+
+    /// Stop playing, and bring the device back into a neutral state.
+    pub fn stop(&mut self) -> color_eyre::Result<()> {
+        self.client
+            .seq
+            .control_queue(self.queue, EventType::Stop, 0, None)?;
+        // Also removes the events that were already scheduled
+        self.client.seq.drop_output()?;
+        self.silence(true)
+    }
+
+    /// Release all notes and the sustain pedal right away, and optionally reset the device.
+    fn silence(&mut self, reset_device: bool) -> color_eyre::Result<()> {
+        let mut reset = Vec::new();
+        for channel in 0..16 {
+            // Sustain off, then all notes off
+            reset.push(MidiEvent::ControlChange {
+                channel,
+                controller: 64,
+                value: 0,
+            });
+            reset.push(MidiEvent::ControlChange {
+                channel,
+                controller: 123,
+                value: 0,
+            });
+        }
+        if reset_device {
+            // GM Reset, for devices supporting it
+            reset.push(MidiEvent::SysEx {
+                data: vec![0x7E, 0x7F, 0x09, 0x01, 0xF7],
+            });
+        }
+        for payload in reset.iter() {
+            let mut alsa_event = to_alsa_event(payload);
+            alsa_event.set_source(self.port);
+            alsa_event.set_subs();
+            alsa_event.set_direct();
+            self.client.seq.event_output(&mut alsa_event)?;
+        }
+        self.drain_output()?;
+        Ok(())
+    }
+
+    /// Flush buffered events to the sequencer, leaving them buffered if it is busy.
+    fn drain_output(&self) -> color_eyre::Result<()> {
+        match self.client.seq.drain_output() {
+            Ok(_) => Ok(()),
+            Err(err) if err.errno() == alsa::nix::errno::Errno::EAGAIN => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn to_alsa_event(payload: &MidiEvent) -> Event<'static> {
+    let note = |event_type, channel, note, velocity| {
+        Event::new(
+            event_type,
+            &EvNote {
+                channel,
+                note,
+                velocity,
+                off_velocity: 0,
+                duration: 0,
+            },
+        )
+    };
+    let ctrl = |event_type, channel, param, value| {
+        Event::new(
+            event_type,
+            &EvCtrl {
+                channel,
+                param,
+                value,
+            },
+        )
+    };
+    match *payload {
+        MidiEvent::NoteOn {
+            channel,
+            note: key,
+            velocity,
+        } => note(EventType::Noteon, channel, key, velocity),
+        MidiEvent::NoteOff { channel, note: key } => note(EventType::Noteoff, channel, key, 0),
+        MidiEvent::ControlChange {
+            channel,
+            controller,
+            value,
+        } => ctrl(EventType::Controller, channel, controller, value),
+        MidiEvent::KeyPressure {
+            channel,
+            note: key,
+            pressure,
+        } => note(EventType::Keypress, channel, key, pressure),
+        MidiEvent::ProgramChange { channel, program } => {
+            ctrl(EventType::Pgmchange, channel, 0, program.into())
+        }
+        MidiEvent::ChannelPressure { channel, pressure } => {
+            ctrl(EventType::Chanpress, channel, 0, pressure.into())
+        }
+        MidiEvent::PitchBend { channel, value } => {
+            ctrl(EventType::Pitchbend, channel, 0, value.into())
+        }
+        MidiEvent::SysEx { .. } => Event::new_ext(EventType::Sysex, payload.to_bytes()),
+    }
+}
+
+/// Sequencer queues count ticks in 32 bits, which lasts for months at our resolution.
+fn clamp_tick(tick: u64) -> u32 {
+    tick.min(u32::MAX.into()) as u32
+}
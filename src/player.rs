@@ -1,89 +1,222 @@
 //! # Playing MIDI files
 //!
-//! This is currently a hacky implementation that relies on invoking `aplaymidi` for convenience.
-//! Eventually, it would be nice to have a working implementation to talk directly to the platform's
-//! MIDI API. Unfortunately, this isn't entirely trivial within `tokio`.
+//! Playback goes through a sequencer queue of the platform's MIDI API (see [`midi::Playback`]).
+//! The queue takes care of the timing, a task only needs to keep feeding it events a little ahead
+//! of time.
 
-use std::{pin::Pin, process::Stdio, sync::Arc};
+use std::{
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use lazy_static::lazy_static;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use tokio::{
     select,
-    sync::{broadcast, oneshot, Mutex},
+    sync::{broadcast, mpsc, oneshot, Mutex},
     task::JoinHandle,
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error};
 
+use crate::midi::{self, MidiEvent, RecordEvent};
+
+/// How far ahead of the current position events are handed to the sequencer, in real time, i.e.
+/// independent of the tempo factor.
+const LOOKAHEAD_SECONDS: f64 = 1.0;
+
+/// How often more events are handed to the sequencer, must be well below the lookahead.
+const SCHEDULE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Supported factors for playing faster or slower.
+pub const TEMPO_FACTOR_RANGE: RangeInclusive<f64> = 0.25..=4.0;
+
+/// Supported transpositions, in semitones.
+pub const TRANSPOSE_RANGE: RangeInclusive<i8> = -24..=24;
+
+/// How a recording is played. These only affect what is sent to the device, not the stored data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayOptions {
+    /// Playback speed relative to the recording, e.g. 0.7 for playing at 70% speed
+    pub tempo_factor: f64,
+    /// Semitones to shift all notes by
+    pub transpose: i8,
+    /// Section to play repeatedly instead of the whole recording
+    pub looping: Option<PlayLoop>,
+}
+
+impl Default for PlayOptions {
+    fn default() -> Self {
+        Self {
+            tempo_factor: 1.0,
+            transpose: 0,
+            looping: None,
+        }
+    }
+}
+
+/// A section of a recording to be played repeatedly ("A-B loop"), for practising a passage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayLoop {
+    /// Time from the beginning of the recording
+    pub start: Duration,
+    /// Time from the beginning of the recording, after which playback jumps back to `start`
+    pub end: Duration,
+    /// How often the section is played, endlessly if not given
+    pub repetitions: Option<u32>,
+}
+
+impl PlayLoop {
+    pub fn to_section(self) -> midi::LoopSection {
+        midi::LoopSection {
+            start: midi::seconds_to_ticks(self.start.as_secs_f64()),
+            end: midi::seconds_to_ticks(self.end.as_secs_f64()),
+            repetitions: self.repetitions,
+        }
+    }
+}
+
+impl PlayOptions {
+    /// Shift the notes of the events, dropping those that end up outside of the MIDI range.
+    ///
+    /// The tempo factor is applied by the playback itself, so that positions within the recording
+    /// (for seeking) stay the same.
+    pub fn transpose_events(&self, events: Vec<RecordEvent>) -> Vec<RecordEvent> {
+        if self.transpose == 0 {
+            return events;
+        }
+        events
+            .into_iter()
+            .filter_map(|mut event| {
+                match &mut event.payload {
+                    MidiEvent::NoteOn { note, .. }
+                    | MidiEvent::NoteOff { note, .. }
+                    | MidiEvent::KeyPressure { note, .. } => {
+                        let transposed = i16::from(*note) + i16::from(self.transpose);
+                        *note = u8::try_from(transposed).ok().filter(|note| *note <= 127)?;
+                    }
+                    _ => {}
+                }
+                Some(event)
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug)]
 pub struct MidiPlayer {
     cancellation_token: CancellationToken,
+    commands: mpsc::UnboundedSender<PlayerCommand>,
+    /// Playback position in ticks, kept up to date by the player task
+    position: Arc<AtomicU64>,
 }
 
-lazy_static!(
-    /// MIDI file that sends a single GM Reset message.
-    static ref GM_RESET_MESSAGE_MID: Vec<u8> = {
-        let mut smf = midly::Smf::new(midly::Header::new(
-            midly::Format::SingleTrack,
-            // Timing doesn't matter since we just send a single message
-            midly::Timing::Metrical(midly::num::u15::new(96)),
-        ));
-        let track = vec![
-            midly::TrackEvent {
-                delta: 0.into(),
-                // `GM Reset` message
-                kind: midly::TrackEventKind::SysEx(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]),
-            },
-            midly::TrackEvent {
-                delta: 0.into(),
-                kind: midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
-            }
-        ];
-        smf.tracks.push(track);
-
-        let mut output = Vec::new();
-        smf.write_std(&mut output).expect("Writing to a vector shouldn't fail");
-        output
-    };
-);
+#[derive(Debug, Clone, Copy)]
+enum PlayerCommand {
+    Pause,
+    Resume,
+    Seek(u64),
+}
 
 impl MidiPlayer {
-    pub async fn new(
-        output: String,
-        source: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
-    ) -> std::io::Result<(Self, oneshot::Receiver<()>)> {
-        // Spawn a new process for the given play request
-        let mut proc = spawn_aplaymidi(output.as_str(), 2).await?;
-
-        let stdin = proc.stdin.take().unwrap();
-        tokio::spawn(feed_aplaymidi(stdin, source));
-
+    /// Start playing `playback`, which was set up for the given tempo factor.
+    pub fn new(mut playback: midi::Playback, tempo_factor: f64) -> (Self, oneshot::Receiver<()>) {
         let cancellation_token = CancellationToken::new();
         let (completed_tx, completed_rx) = oneshot::channel::<()>();
+        let (commands_tx, mut commands) = mpsc::unbounded_channel();
+        let position = Arc::new(AtomicU64::new(0));
 
         tokio::spawn({
             let cancellation_token = cancellation_token.clone();
-            let output = output.clone();
+            let position = position.clone();
             async move {
-                select! {
-                    _ = cancellation_token.cancelled() => {
-                        let _ = proc.kill().await;
-                        reset_output(&output).await;
-                    }
-                    _ = proc.wait() => {
-                        // Normal exit
-                        let _ = completed_tx.send(());
+                // Ticks of the recording, which pass faster when playing faster
+                let lookahead = midi::seconds_to_ticks(LOOKAHEAD_SECONDS * tempo_factor);
+                let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+                loop {
+                    select! {
+                        _ = cancellation_token.cancelled() => {
+                            if let Err(err) = playback.stop() {
+                                error!("Failed to stop playback: {err}");
+                            }
+                            break;
+                        }
+                        Some(command) = commands.recv() => {
+                            let result = match command {
+                                PlayerCommand::Pause => playback.pause(),
+                                PlayerCommand::Resume => playback.resume(),
+                                PlayerCommand::Seek(tick) => {
+                                    position.store(tick, Ordering::Relaxed);
+                                    playback.seek(tick)
+                                }
+                            };
+                            if let Err(err) = result {
+                                error!("Failed to {command:?} playback: {err}");
+                            }
+                        }
+                        _ = interval.tick() => {
+                            let finished = playback
+                                .schedule(lookahead)
+                                .and_then(|()| {
+                                    position.store(playback.position()?, Ordering::Relaxed);
+                                    playback.is_finished()
+                                });
+                            match finished {
+                                Ok(false) => {}
+                                Ok(true) => {
+                                    // Normal exit
+                                    debug!("Playback finished");
+                                    let _ = completed_tx.send(());
+                                    break;
+                                }
+                                Err(err) => {
+                                    error!("Playback failed: {err}");
+                                    let _ = playback.stop();
+                                    break;
+                                }
+                            }
+                        }
                     }
                 }
             }
         });
 
-        Ok((Self { cancellation_token }, completed_rx))
+        let player = Self {
+            cancellation_token,
+            commands: commands_tx,
+            position,
+        };
+        (player, completed_rx)
     }
 
     pub fn stop(&self) {
         self.cancellation_token.cancel();
     }
+
+    /// Hold playback, releasing all notes on the device.
+    pub fn pause(&self) {
+        let _ = self.commands.send(PlayerCommand::Pause);
+    }
+
+    pub fn resume(&self) {
+        let _ = self.commands.send(PlayerCommand::Resume);
+    }
+
+    /// Jump to the given time from the beginning of the recording.
+    pub fn seek(&self, position: Duration) {
+        let tick = midi::seconds_to_ticks(position.as_secs_f64());
+        let _ = self.commands.send(PlayerCommand::Seek(tick));
+    }
+
+    /// Time from the beginning of the recording.
+    pub fn position(&self) -> Duration {
+        let tick = self.position.load(Ordering::Relaxed);
+        Duration::from_secs_f64(midi::ticks_to_seconds(tick))
+    }
 }
 
 impl Drop for MidiPlayer {
@@ -92,64 +225,103 @@ impl Drop for MidiPlayer {
     }
 }
 
-async fn spawn_aplaymidi(output: &str, delay: u32) -> std::io::Result<tokio::process::Child> {
-    tokio::process::Command::new("aplaymidi")
-        .arg("-p")
-        .arg(output)
-        .arg("-d")
-        .arg(delay.to_string())
-        .arg("-") // read from stdin
-        .stdin(Stdio::piped())
-        .spawn()
+/// Identifies an entry of the queue, so that the same item can be queued more than once.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EntryId(pub u64);
+
+#[derive(Debug, Clone)]
+pub struct QueueEntry<T> {
+    pub id: EntryId,
+    pub item: T,
 }
 
-async fn feed_aplaymidi(
-    mut stdin: tokio::process::ChildStdin,
-    mut source: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
-) {
-    match tokio::io::copy(&mut source, &mut stdin).await {
-        Ok(count) => debug!("Played {count} MIDI bytes"),
-        Err(err) => {
-            error!("Failed to send data to aplaymidi: {err}")
-        }
-    }
+/// What happens when an entry has been played.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepeatMode {
+    /// Continue with the next entry, and stop once the queue is empty
+    Off,
+    /// Play the same entry again
+    One,
+    /// Put the entry back at the end of the queue
+    All,
 }
 
-async fn reset_output(output: &str) {
-    if let Ok(mut reset_cmd) = spawn_aplaymidi(output, 0).await {
-        let source = Box::pin(GM_RESET_MESSAGE_MID.as_slice());
-        let stdin = reset_cmd.stdin.take().unwrap();
-        feed_aplaymidi(stdin, source).await;
-        let _ = reset_cmd.wait().await;
-    }
+#[derive(Debug, Clone)]
+pub struct QueueSettings {
+    pub repeat: RepeatMode,
+    /// Pick the next entry at random instead of in order
+    pub shuffle: bool,
+    /// Pause between two entries
+    pub gap: Duration,
 }
 
+/// Plays one item at a time, followed by the entries of a queue.
+///
+/// The queue doesn't know how to turn an item into a [`midi::Playback`], so advancing to the next
+/// entry is left to the owner: once an item was played until the end, [`QueueEvent::Finished`] is
+/// sent, and the owner is expected to wait for the gap, [`Self::take_next`] entry and [`Self::play`]
+/// it (unless the [`Self::generation`] changed in the meantime).
 #[derive(Debug)]
 pub struct MidiPlayQueue<T> {
     shared: Arc<Mutex<QueueShared<T>>>,
     player: Option<(MidiPlayer, JoinHandle<()>)>,
     tx: Arc<broadcast::Sender<QueueEvent<T>>>,
+    /// Entries to be played after the current one
+    entries: Vec<QueueEntry<T>>,
+    settings: QueueSettings,
+    next_entry_id: u64,
+    /// Changes whenever playback is started or stopped
+    generation: u64,
 }
 
 #[derive(Debug)]
 struct QueueShared<T> {
-    current: Option<T>,
+    current: Option<QueueEntry<T>>,
+    paused: bool,
+    options: PlayOptions,
 }
 
 #[derive(Debug, Clone)]
 pub enum QueueEvent<T> {
     PlaybackStart(T),
     PlaybackStop(T),
+    /// The entry was played until the end (sent before [`QueueEvent::PlaybackStop`]), along with
+    /// the generation it was started in
+    Finished(QueueEntry<T>, u64),
+    Paused(T),
+    Resumed(T),
+    Seeked(T, Duration),
+    /// The entries or the settings of the queue changed
+    Changed(Vec<QueueEntry<T>>, QueueSettings),
+}
+
+/// What the player is currently playing.
+#[derive(Debug, Clone)]
+pub struct PlayStatus<T> {
+    pub current: T,
+    pub paused: bool,
+    /// Time from the beginning of the recording
+    pub position: Duration,
+    pub options: PlayOptions,
 }
 
 impl<T: Clone + Send + 'static> MidiPlayQueue<T> {
-    pub fn new() -> Self {
+    pub fn new(settings: QueueSettings) -> Self {
         let (tx, _rx) = broadcast::channel(16);
 
         Self {
-            shared: Arc::new(Mutex::new(QueueShared { current: None })),
+            shared: Arc::new(Mutex::new(QueueShared {
+                current: None,
+                paused: false,
+                options: PlayOptions::default(),
+            })),
             player: None,
             tx: Arc::new(tx),
+            entries: Vec::new(),
+            settings,
+            next_entry_id: 0,
+            generation: 0,
         }
     }
 
@@ -157,55 +329,317 @@ impl<T: Clone + Send + 'static> MidiPlayQueue<T> {
         self.tx.subscribe()
     }
 
-    pub async fn play(
+    /// Play an item right away, instead of whatever is playing. The queue is left alone.
+    pub async fn play(&mut self, item: T, playback: midi::Playback, options: PlayOptions) {
+        let entry = self.new_entry(item);
+        self.play_entry(entry, playback, options).await
+    }
+
+    /// Play an entry taken from the queue (or a new one), with the options that were used to set up
+    /// `playback`.
+    pub async fn play_entry(
         &mut self,
-        token: T,
-        output: String,
-        source: Pin<Box<dyn tokio::io::AsyncRead + Send>>,
-    ) -> std::io::Result<()> {
+        entry: QueueEntry<T>,
+        playback: midi::Playback,
+        options: PlayOptions,
+    ) {
         if let Some((player, waiter)) = self.player.take() {
             player.stop();
             let _ = waiter.await;
         }
+        self.generation += 1;
 
-        let (player, completed) = MidiPlayer::new(output, source).await?;
+        let (player, completed) = MidiPlayer::new(playback, options.tempo_factor);
 
-        let _ = self.tx.send(QueueEvent::PlaybackStart(token.clone()));
+        let _ = self.tx.send(QueueEvent::PlaybackStart(entry.item.clone()));
 
         {
             let mut state = self.shared.lock().await;
-            state.current = Some(token.clone());
+            state.current = Some(entry.clone());
+            state.paused = false;
+            state.options = options;
         }
 
         let waiter = tokio::spawn({
             let tx = self.tx.clone();
             let shared = self.shared.clone();
+            let generation = self.generation;
             async move {
-                // Wait for player process to stop
-                let _ = completed.await;
+                // Wait for player to stop
+                let finished = completed.await.is_ok();
                 {
                     let mut state = shared.lock().await;
                     state.current = None;
                 }
                 // Ignore errors, we don't care if anyone listens
-                let _ = tx.send(QueueEvent::PlaybackStop(token));
+                if finished {
+                    let _ = tx.send(QueueEvent::Finished(entry.clone(), generation));
+                }
+                let _ = tx.send(QueueEvent::PlaybackStop(entry.item));
             }
         });
 
         self.player = Some((player, waiter));
-
-        Ok(())
     }
 
     pub async fn stop(&mut self) {
+        self.generation += 1;
         if let Some((player, waiter)) = self.player.take() {
             player.stop();
             let _ = waiter.await;
         }
     }
 
-    pub async fn current(&self) -> Option<T> {
+    /// Changes whenever playback is started or stopped, so that it can be checked whether anything
+    /// happened while waiting.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The entry being played, if any.
+    pub async fn current(&self) -> Option<QueueEntry<T>> {
         let state = self.shared.lock().await;
         state.current.clone()
     }
+
+    /// Entries to be played after the current one, in order (unless shuffling).
+    pub fn entries(&self) -> &[QueueEntry<T>] {
+        &self.entries
+    }
+
+    pub fn settings(&self) -> &QueueSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: QueueSettings) {
+        self.settings = settings;
+        self.notify_changed();
+    }
+
+    /// Add an item to the end of the queue.
+    pub fn enqueue(&mut self, item: T) -> EntryId {
+        let entry = self.new_entry(item);
+        let id = entry.id;
+        self.entries.push(entry);
+        self.notify_changed();
+        id
+    }
+
+    /// Remove an entry from the queue, returns `false` if there is no such entry.
+    pub fn remove(&mut self, id: EntryId) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.id != id);
+        let removed = self.entries.len() != len;
+        if removed {
+            self.notify_changed();
+        }
+        removed
+    }
+
+    /// Move an entry to the given index of the queue (or to the end), returns `false` if there is no
+    /// such entry.
+    pub fn move_entry(&mut self, id: EntryId, index: usize) -> bool {
+        match self.entries.iter().position(|entry| entry.id == id) {
+            Some(from) => {
+                let entry = self.entries.remove(from);
+                let index = index.min(self.entries.len());
+                self.entries.insert(index, entry);
+                self.notify_changed();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.notify_changed();
+    }
+
+    /// Remove the entry to be played after `previous` from the queue, taking the repeat and shuffle
+    /// modes into account. When `skipped`, `previous` is not repeated right away.
+    pub fn take_next(
+        &mut self,
+        previous: Option<QueueEntry<T>>,
+        skipped: bool,
+    ) -> Option<QueueEntry<T>> {
+        let mut candidates = self.entries.len();
+        if let Some(previous) = previous {
+            match self.settings.repeat {
+                RepeatMode::One if !skipped => return Some(previous),
+                RepeatMode::Off => {}
+                RepeatMode::One | RepeatMode::All => {
+                    // Doesn't need to be a candidate for shuffling unless it's the only entry
+                    self.entries.push(previous);
+                    candidates = candidates.max(1);
+                }
+            }
+        }
+        if self.entries.is_empty() {
+            return None;
+        }
+        let index = if self.settings.shuffle {
+            rand::thread_rng().gen_range(0..candidates)
+        } else {
+            0
+        };
+        let entry = self.entries.remove(index);
+        self.notify_changed();
+        Some(entry)
+    }
+
+    /// Pause playback, returns `false` if nothing is playing.
+    pub async fn pause(&mut self) -> bool {
+        self.set_paused(true).await
+    }
+
+    /// Resume paused playback, returns `false` if nothing is playing.
+    pub async fn resume(&mut self) -> bool {
+        self.set_paused(false).await
+    }
+
+    async fn set_paused(&mut self, paused: bool) -> bool {
+        let mut state = self.shared.lock().await;
+        let (current, (player, _)) = match (state.current.clone(), self.player.as_ref()) {
+            (Some(current), Some(player)) => (current.item, player),
+            _ => return false,
+        };
+        if state.paused != paused {
+            state.paused = paused;
+            if paused {
+                player.pause();
+                let _ = self.tx.send(QueueEvent::Paused(current));
+            } else {
+                player.resume();
+                let _ = self.tx.send(QueueEvent::Resumed(current));
+            }
+        }
+        true
+    }
+
+    /// Jump to the given time of the current recording, returns `false` if nothing is playing.
+    pub async fn seek(&mut self, position: Duration) -> bool {
+        let state = self.shared.lock().await;
+        match (state.current.clone(), self.player.as_ref()) {
+            (Some(current), Some((player, _))) => {
+                player.seek(position);
+                let _ = self.tx.send(QueueEvent::Seeked(current.item, position));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub async fn status(&self) -> Option<PlayStatus<T>> {
+        let state = self.shared.lock().await;
+        match (state.current.clone(), self.player.as_ref()) {
+            (Some(current), Some((player, _))) => Some(PlayStatus {
+                current: current.item,
+                paused: state.paused,
+                position: player.position(),
+                options: state.options,
+            }),
+            _ => None,
+        }
+    }
+
+    fn new_entry(&mut self, item: T) -> QueueEntry<T> {
+        self.next_entry_id += 1;
+        QueueEntry {
+            id: EntryId(self.next_entry_id),
+            item,
+        }
+    }
+
+    fn notify_changed(&self) {
+        let _ = self.tx.send(QueueEvent::Changed(
+            self.entries.clone(),
+            self.settings.clone(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_queue(repeat: RepeatMode, shuffle: bool, items: &[u32]) -> MidiPlayQueue<u32> {
+        let mut queue = MidiPlayQueue::new(QueueSettings {
+            repeat,
+            shuffle,
+            gap: Duration::ZERO,
+        });
+        for item in items {
+            queue.enqueue(*item);
+        }
+        queue
+    }
+
+    fn items(queue: &MidiPlayQueue<u32>) -> Vec<u32> {
+        queue.entries().iter().map(|entry| entry.item).collect()
+    }
+
+    /// Play the whole queue, returns the items in the order they were played.
+    fn play_all(queue: &mut MidiPlayQueue<u32>) -> Vec<u32> {
+        let mut played = vec![];
+        let mut previous = None;
+        while let Some(entry) = queue.take_next(previous, false) {
+            played.push(entry.item);
+            previous = Some(entry);
+        }
+        played
+    }
+
+    #[test]
+    fn take_next_without_repeat() {
+        let mut queue = new_queue(RepeatMode::Off, false, &[1, 2, 3]);
+        assert_eq!(play_all(&mut queue), [1, 2, 3]);
+        assert!(queue.entries().is_empty());
+
+        let mut queue = new_queue(RepeatMode::Off, true, &[1, 2, 3]);
+        let mut played = play_all(&mut queue);
+        played.sort_unstable();
+        assert_eq!(played, [1, 2, 3]);
+    }
+
+    #[test]
+    fn take_next_repeat_one() {
+        for shuffle in [false, true] {
+            let mut queue = new_queue(RepeatMode::One, shuffle, &[1, 2]);
+            let first = queue.take_next(None, false).unwrap();
+            let again = queue.take_next(Some(first.clone()), false).unwrap();
+            assert_eq!(again.id, first.id);
+
+            // Skipping continues with the other entry, and keeps the skipped one queued
+            let other = queue.take_next(Some(again), true).unwrap();
+            assert_ne!(other.item, first.item);
+            assert_eq!(items(&queue), [first.item]);
+        }
+    }
+
+    #[test]
+    fn take_next_repeat_all() {
+        let mut queue = new_queue(RepeatMode::All, false, &[1, 2, 3]);
+        let mut previous = None;
+        let mut played = vec![];
+        for _ in 0..6 {
+            let entry = queue.take_next(previous, false).unwrap();
+            played.push(entry.item);
+            previous = Some(entry);
+        }
+        assert_eq!(played, [1, 2, 3, 1, 2, 3]);
+
+        // The entry that was just played is only picked again if it's the only one
+        let mut queue = new_queue(RepeatMode::All, true, &[1, 2]);
+        let mut previous = queue.take_next(None, false).unwrap();
+        for _ in 0..10 {
+            let entry = queue.take_next(Some(previous.clone()), false).unwrap();
+            assert_ne!(entry.item, previous.item);
+            previous = entry;
+        }
+        let mut queue = new_queue(RepeatMode::All, true, &[1]);
+        let first = queue.take_next(None, false).unwrap();
+        let again = queue.take_next(Some(first.clone()), false).unwrap();
+        assert_eq!(again.id, first.id);
+    }
 }
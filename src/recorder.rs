@@ -1,157 +1,489 @@
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use tracing::{info, trace};
+use tokio::sync::oneshot;
+use tracing::{error, info, trace};
 
 use crate::{
     app::Shared,
-    midi::{self, RecordEvent},
+    config::{GestureAction, GestureConfig, SegmentationPolicy},
+    journal::Journal,
+    midi::{self, Device, DeviceInfo, MidiEvent, RecordEvent, PEDAL_CONTROLLERS},
+    store::StopReason,
 };
 
+use self::gestures::GestureDetector;
+
+mod gestures;
+
+/// Record songs until the device is disconnected, or the recorder is told to `stop`.
 pub async fn run_recorder(
     app: Arc<Shared>,
-    mut recorder: midi::Recorder,
+    recorder: midi::Recorder,
+    stop: oneshot::Receiver<()>,
+    device: Device,
+    info: DeviceInfo,
+    policy: SegmentationPolicy,
+    gestures: Vec<GestureConfig>,
 ) -> color_eyre::Result<()> {
-    loop {
-        info!("Waiting for song to start");
-        let event = recorder.next().await?;
+    let mut gestures = GestureDetector::new(gestures);
+    let mut recorder = StoppableRecorder {
+        recorder,
+        stop,
+        stopped: false,
+    };
 
-        if let Some(event) = event {
-            app.start_recording().await;
+    let mut next_start = None;
+    loop {
+        let start = match next_start.take() {
+            Some(start) => start,
+            None => {
+                info!("Waiting for song to start");
+                match recorder.next().await? {
+                    // Releasing the keys of a gesture shouldn't start a new song
+                    Some(event) if gestures.suppress(&event.payload) => continue,
+                    Some(event) => SongStart::Event(event),
+                    None => break,
+                }
+            }
+        };
 
-            let (song, stop_reason) = record_song(event, &mut recorder).await?;
+        let mut journal = app.start_recording(&device, &info).await;
 
-            app.finish_recording(song).await;
+        let song = record_song(start, &mut recorder, &policy, &mut gestures, &mut journal).await?;
 
-            if let StopReason::Disconnect = stop_reason {
-                info!("Recording device has been disconnected");
-                break;
-            }
+        if let Some(GestureAction::Discard) = song.action {
+            info!("Discarding song");
+            app.discard_recording(&device, journal).await;
         } else {
+            app.finish_recording(&device, &info, song.events, song.stop_reason, journal)
+                .await;
+        }
+
+        match song.action {
+            Some(GestureAction::Favourite) => app.mark_last_recording_favourite(&info).await,
+            Some(GestureAction::Replay) => app.replay_last_recording(&device, &info).await,
+            _ => {}
+        }
+
+        if let StopReason::Disconnect = song.stop_reason {
+            info!("Recording device has been disconnected");
             break;
         }
+        next_start = song.continuation;
     }
     Ok(())
 }
 
-/// Describes what caused the end of the recording.
-pub enum StopReason {
-    /// Pianist was idle for too long
-    Idle,
-    /// Device got disconnected/turned off
-    Disconnect,
+/// How a song begins.
+pub enum SongStart {
+    /// With the first event played
+    Event(RecordEvent),
+    /// Right where the previous song was split, by pressing the keys and pedals that were still
+    /// held down at the time again
+    Split { tick: u64, held: Vec<MidiEvent> },
 }
 
+pub struct Song {
+    pub events: Vec<RecordEvent>,
+    pub stop_reason: StopReason,
+    /// Action of the gesture that ended the song
+    pub action: Option<GestureAction>,
+    /// Start of the next song, if it continues right away because this one was split
+    pub continuation: Option<SongStart>,
+}
+
+/// Record events until the song ends.
 pub async fn record_song(
-    mut first_event: RecordEvent,
-    recorder: &mut midi::Recorder,
-) -> color_eyre::Result<(Vec<RecordEvent>, StopReason)> {
+    start: SongStart,
+    recorder: &mut StoppableRecorder,
+    policy: &SegmentationPolicy,
+    gestures: &mut GestureDetector,
+    journal: &mut Option<Journal>,
+) -> color_eyre::Result<Song> {
     info!("Song started");
 
     // Keeping track of keyboard state for idle-detection
     let mut keyboard_state = KeyboardState::new();
-    keyboard_state.update(&first_event);
 
-    const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
-    const MAX_IDLE_PERIODS: usize = 6;
-    let mut idle_periods = 0;
+    let idle_gap = seconds(policy.idle_gap_seconds);
+    let stuck_note_timeout = seconds(policy.stuck_note_timeout_seconds);
+    let max_length = policy.max_length_seconds.map(seconds);
+    let song_start = Instant::now();
+    let mut last_event = Instant::now();
 
-    // Initialize recording
-    trace!("recorded event {:?}", first_event);
-    let start_tick = first_event.timestamp;
-    first_event.timestamp = 0;
-    let mut events = vec![first_event];
+    let mut events = Vec::new();
+    let (start_tick, mut next_event) = match start {
+        SongStart::Event(event) => (event.timestamp, Some(event)),
+        SongStart::Split { tick, held } => {
+            // Not played just now, so these can't be part of a gesture
+            for payload in held {
+                let event = RecordEvent {
+                    timestamp: 0,
+                    payload,
+                };
+                keyboard_state.update(&event);
+                write_journal(journal, &event).await;
+                events.push(event);
+            }
+            (tick, None)
+        }
+    };
+    // Ticks of the device, for knowing where to split
+    let mut last_tick = start_tick;
 
     // Keep recording until idle
-    let stop_reason = loop {
-        match tokio::time::timeout(IDLE_TIMEOUT, recorder.next()).await {
+    let (stop_reason, action) = loop {
+        if let Some(mut event) = next_event.take() {
+            // Update idle detection
+            keyboard_state.update(&event);
+            last_event = Instant::now();
+            last_tick = event.timestamp;
+
+            if let Some(gesture) = gestures.detect(&event.payload) {
+                info!("Recognized {:?} gesture", gesture.action);
+                gestures::strip_gesture(&mut events, &gesture, &event.payload);
+                // Only what is held down for the music is carried over into the next song
+                keyboard_state.forget_gesture(&gesture);
+                // The journal must not bring the gesture back when recovering the recording
+                rewrite_journal(journal, &events).await;
+                break (StopReason::Gesture, Some(gesture.action));
+            }
+
+            // Normalize timestamps relative to first event of this song
+            event.timestamp = event.timestamp.saturating_sub(start_tick);
+            let reltime = recorder.recorder.tick_to_duration(event.timestamp);
+            trace!(
+                "recorded event {:?} at {:.3}s",
+                event,
+                reltime.as_secs_f64()
+            );
+            write_journal(journal, &event).await;
+            events.push(event);
+        }
+
+        // Wake up in time for splitting songs that get too long
+        let mut timeout = idle_gap;
+        if let Some(max_length) = max_length {
+            let remaining = max_length.saturating_sub(song_start.elapsed());
+            if remaining.is_zero() {
+                break (StopReason::MaxLength, None);
+            }
+            timeout = timeout.min(remaining);
+        }
+
+        match tokio::time::timeout(timeout, recorder.next()).await {
             Ok(event) => {
-                if let Some(mut event) = event? {
-                    // Update idle detection
-                    keyboard_state.update(&event);
-                    idle_periods = 0;
-
-                    // Normalize timestamps relative to first event of this song
-                    event.timestamp -= start_tick;
-                    let reltime = recorder.tick_to_duration(event.timestamp);
-                    trace!(
-                        "recorded event {:?} at {:.3}s",
-                        event,
-                        reltime.as_secs_f64()
-                    );
-                    events.push(event);
+                if let Some(event) = event? {
+                    if !gestures.suppress(&event.payload) {
+                        next_event = Some(event);
+                    }
                 } else {
-                    break StopReason::Disconnect;
+                    break (StopReason::Disconnect, None);
                 }
             }
             Err(_elapsed) => {
-                if keyboard_state.is_idle() {
-                    break StopReason::Idle;
-                } else {
-                    idle_periods += 1;
-
-                    // Emergency shutoff (in case state got corrupted)
-                    if idle_periods >= MAX_IDLE_PERIODS {
-                        break StopReason::Idle;
-                    }
+                let idle_for = last_event.elapsed();
+                if idle_for < idle_gap {
+                    // Only woke up to check the maximum length
+                    continue;
+                }
+                if keyboard_state.is_idle(policy.sustain_is_activity) {
+                    break (StopReason::Idle, None);
+                }
+                // Emergency shutoff (in case state got corrupted)
+                if idle_for >= stuck_note_timeout {
+                    break (StopReason::StuckNotes, None);
                 }
             }
         }
     };
+    let mut continuation = None;
+    let split = matches!(stop_reason, StopReason::MaxLength)
+        || matches!(action, Some(GestureAction::Split));
+    if split {
+        // Release whatever is still held at the end of this song, and press it again at the start
+        // of the next one, so that neither contains hanging notes or misses a pedal
+        let split_tick = last_tick + recorder.recorder.duration_to_tick(last_event.elapsed());
+        let held = keyboard_state.press_events();
+        for payload in keyboard_state.release_events() {
+            let event = RecordEvent {
+                timestamp: split_tick.saturating_sub(start_tick),
+                payload,
+            };
+            write_journal(journal, &event).await;
+            events.push(event);
+        }
+        if !held.is_empty() {
+            continuation = Some(SongStart::Split {
+                tick: split_tick,
+                held,
+            });
+        }
+    }
+
     // Ticks are already normalized here
-    let last_tick = events
-        .last()
-        .expect("we have at least `first_event`")
-        .timestamp;
-    let duration = recorder.tick_to_duration(last_tick);
+    let end_tick = events.last().map_or(0, |event| event.timestamp);
+    let duration = recorder.recorder.tick_to_duration(end_tick);
     info!(
         "Song ended, duration {:.3}s, {} events",
         duration.as_secs_f64(),
         events.len()
     );
 
-    // TODO: stream events to disk - do not keep them in memory
-    Ok((events, stop_reason))
+    // TODO: do not keep events in memory, they are in the journal anyway
+    Ok(Song {
+        events,
+        stop_reason,
+        action,
+        continuation,
+    })
+}
+
+/// A recorder that behaves as if the device was disconnected once it is told to stop.
+pub struct StoppableRecorder {
+    recorder: midi::Recorder,
+    stop: oneshot::Receiver<()>,
+    stopped: bool,
+}
+
+impl StoppableRecorder {
+    async fn next(&mut self) -> color_eyre::Result<Option<RecordEvent>> {
+        if self.stopped {
+            return Ok(None);
+        }
+        tokio::select! {
+            event = self.recorder.next() => event,
+            // Also when the sender was dropped
+            _ = &mut self.stop => {
+                self.stopped = true;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Convert a duration from the config, where it has been checked to be positive already.
+fn seconds(seconds: f64) -> Duration {
+    // Durations can't get arbitrarily long, but a century is as good as forever here
+    Duration::from_secs_f64(seconds.min(f64::from(u32::MAX)))
+}
+
+/// Append the event to the journal, giving up on journaling the recording if that fails.
+async fn write_journal(journal: &mut Option<Journal>, event: &RecordEvent) {
+    if let Some(inner) = journal.as_mut() {
+        if let Err(err) = inner.append(event).await {
+            error!("Failed to write journal, continuing without: {}", err);
+            abandon_journal(journal).await;
+        }
+    }
+}
+
+/// Replace the events of the journal, giving up on journaling the recording if that fails.
+async fn rewrite_journal(journal: &mut Option<Journal>, events: &[RecordEvent]) {
+    if let Some(inner) = journal.as_mut() {
+        if let Err(err) = inner.rewrite(events).await {
+            error!("Failed to rewrite journal, continuing without: {}", err);
+            abandon_journal(journal).await;
+        }
+    }
+}
+
+async fn abandon_journal(journal: &mut Option<Journal>) {
+    // Remove it, a partial recovery would only be confusing
+    if let Some(inner) = journal.take() {
+        if let Err(err) = inner.remove().await {
+            error!("Failed to remove journal: {}", err);
+        }
+    }
 }
 
 struct KeyboardState {
-    sustain_channels: HashSet<u8>,
-    pressed_keys: HashSet<(u8, u8)>,
+    /// Velocities of the keys that are held down
+    pressed_keys: BTreeMap<(u8, u8), u8>,
+    /// Values of the pedals that are not fully released
+    pedals: BTreeMap<(u8, u32), i32>,
 }
 
 impl KeyboardState {
     fn update(&mut self, event: &RecordEvent) {
         match event.payload {
-            midi::MidiEvent::NoteOn { channel, note, .. } => {
-                self.pressed_keys.insert((channel, note));
+            MidiEvent::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => {
+                self.pressed_keys.insert((channel, note), velocity);
             }
-            midi::MidiEvent::NoteOff { channel, note } => {
+            MidiEvent::NoteOff { channel, note } => {
                 self.pressed_keys.remove(&(channel, note));
             }
-            midi::MidiEvent::ControlChange {
+            MidiEvent::ControlChange {
                 channel,
                 controller,
                 value,
-            } => {
-                if controller == 64 {
-                    // Sustain
-                    if value >= 64 {
-                        self.sustain_channels.insert(channel);
-                    } else {
-                        self.sustain_channels.remove(&channel);
-                    }
+            } if PEDAL_CONTROLLERS.contains(&controller) => {
+                if value > 0 {
+                    self.pedals.insert((channel, controller), value);
+                } else {
+                    self.pedals.remove(&(channel, controller));
                 }
             }
+            _ => {}
         }
     }
 
-    fn is_idle(&self) -> bool {
-        self.sustain_channels.is_empty() && self.pressed_keys.is_empty()
+    fn is_idle(&self, sustain_is_activity: bool) -> bool {
+        let sustain = self
+            .pedals
+            .iter()
+            .any(|(&(_, controller), &value)| controller == 64 && value >= 64);
+        (!sustain_is_activity || !sustain) && self.pressed_keys.is_empty()
+    }
+
+    /// Treat the keys (and the sustain pedal) of a recognized gesture as released.
+    fn forget_gesture(&mut self, gesture: &GestureConfig) {
+        self.pressed_keys
+            .retain(|&(_, note), _| !gesture.keys.contains(&note));
+        if gesture.sustain {
+            self.pedals.retain(|&(_, controller), _| controller != 64);
+        }
+    }
+
+    /// Events for pressing all keys and pedals that are currently held down.
+    fn press_events(&self) -> Vec<MidiEvent> {
+        // Pedals first, so that the notes are sustained right away
+        let pedals =
+            self.pedals.iter().map(
+                |(&(channel, controller), &value)| MidiEvent::ControlChange {
+                    channel,
+                    controller,
+                    value,
+                },
+            );
+        let notes = self
+            .pressed_keys
+            .iter()
+            .map(|(&(channel, note), &velocity)| MidiEvent::NoteOn {
+                channel,
+                note,
+                velocity,
+            });
+        pedals.chain(notes).collect()
+    }
+
+    /// Events for releasing all keys and pedals that are currently held down.
+    fn release_events(&self) -> Vec<MidiEvent> {
+        let notes = self
+            .pressed_keys
+            .keys()
+            .map(|&(channel, note)| MidiEvent::NoteOff { channel, note });
+        let pedals = self
+            .pedals
+            .keys()
+            .map(|&(channel, controller)| MidiEvent::ControlChange {
+                channel,
+                controller,
+                value: 0,
+            });
+        notes.chain(pedals).collect()
     }
 
     fn new() -> Self {
         Self {
-            sustain_channels: HashSet::new(),
-            pressed_keys: HashSet::new(),
+            pressed_keys: BTreeMap::new(),
+            pedals: BTreeMap::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(payload: MidiEvent) -> RecordEvent {
+        RecordEvent {
+            timestamp: 0,
+            payload,
+        }
+    }
+
+    #[test]
+    fn keyboard_state_held_keys_and_pedals() {
+        let mut state = KeyboardState::new();
+        for payload in [
+            MidiEvent::NoteOn {
+                channel: 0,
+                note: 60,
+                velocity: 70,
+            },
+            MidiEvent::NoteOn {
+                channel: 0,
+                note: 64,
+                velocity: 80,
+            },
+            MidiEvent::NoteOff {
+                channel: 0,
+                note: 60,
+            },
+            MidiEvent::ControlChange {
+                channel: 0,
+                controller: 64,
+                value: 127,
+            },
+            MidiEvent::ControlChange {
+                channel: 0,
+                controller: 67,
+                value: 40,
+            },
+            MidiEvent::ControlChange {
+                channel: 0,
+                controller: 67,
+                value: 0,
+            },
+        ] {
+            state.update(&event(payload));
+        }
+
+        assert!(!state.is_idle(false));
+        assert_eq!(
+            state.press_events(),
+            [
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 64,
+                    value: 127,
+                },
+                MidiEvent::NoteOn {
+                    channel: 0,
+                    note: 64,
+                    velocity: 80,
+                },
+            ]
+        );
+        assert_eq!(
+            state.release_events(),
+            [
+                MidiEvent::NoteOff {
+                    channel: 0,
+                    note: 64,
+                },
+                MidiEvent::ControlChange {
+                    channel: 0,
+                    controller: 64,
+                    value: 0,
+                },
+            ]
+        );
+
+        state.update(&event(MidiEvent::NoteOff {
+            channel: 0,
+            note: 64,
+        }));
+        assert!(state.is_idle(false));
+        assert!(!state.is_idle(true));
+    }
+}
@@ -0,0 +1,281 @@
+//! # Keyboard gestures
+//!
+//! A gesture is a combination of keys held down together, optionally completed by tapping the
+//! sustain pedal. Gestures are not part of the music: the events making them up are removed from
+//! the recording, including the release of the keys (and the pedal) after the gesture was
+//! recognized.
+
+use std::collections::HashSet;
+
+use tracing::warn;
+
+use crate::{
+    config::GestureConfig,
+    midi::{MidiEvent, RecordEvent},
+};
+
+/// Controller number of the sustain pedal.
+const SUSTAIN_CONTROLLER: u32 = 64;
+
+#[derive(Debug)]
+pub struct GestureDetector {
+    gestures: Vec<GestureConfig>,
+    held_keys: HashSet<u8>,
+    sustain: bool,
+    /// Keys of a recognized gesture that have not been released yet
+    suppressed_keys: HashSet<u8>,
+    /// Whether the sustain pedal was part of a recognized gesture and has not been released yet
+    suppressed_sustain: bool,
+}
+
+impl GestureDetector {
+    pub fn new(gestures: Vec<GestureConfig>) -> Self {
+        let gestures = gestures
+            .into_iter()
+            .filter(|gesture| {
+                if gesture.keys.is_empty() {
+                    warn!("Ignoring {:?} gesture without any keys", gesture.action);
+                }
+                !gesture.keys.is_empty()
+            })
+            .collect();
+        Self {
+            gestures,
+            held_keys: HashSet::new(),
+            sustain: false,
+            suppressed_keys: HashSet::new(),
+            suppressed_sustain: false,
+        }
+    }
+
+    /// Whether the event is the tail end of a recognized gesture, and should be dropped.
+    pub fn suppress(&mut self, event: &MidiEvent) -> bool {
+        match *event {
+            MidiEvent::NoteOff { note, .. } if self.suppressed_keys.remove(&note) => {
+                self.held_keys.remove(&note);
+                true
+            }
+            MidiEvent::ControlChange {
+                controller, value, ..
+            } if controller == SUSTAIN_CONTROLLER && self.suppressed_sustain => {
+                if value < 64 {
+                    self.sustain = false;
+                    self.suppressed_sustain = false;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Keep track of the event, returning the gesture it completes (if any).
+    ///
+    /// The event itself belongs to the gesture in that case.
+    pub fn detect(&mut self, event: &MidiEvent) -> Option<GestureConfig> {
+        let completed = match *event {
+            MidiEvent::NoteOn { note, .. } => {
+                self.held_keys.insert(note);
+                self.gestures.iter().find(|gesture| {
+                    !gesture.sustain && gesture.keys.contains(&note) && self.holds(gesture)
+                })
+            }
+            MidiEvent::NoteOff { note, .. } => {
+                self.held_keys.remove(&note);
+                None
+            }
+            MidiEvent::ControlChange {
+                controller, value, ..
+            } if controller == SUSTAIN_CONTROLLER => {
+                let pressed = value >= 64 && !self.sustain;
+                self.sustain = value >= 64;
+                if pressed {
+                    self.gestures
+                        .iter()
+                        .find(|gesture| gesture.sustain && self.holds(gesture))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let gesture = completed.cloned()?;
+        self.suppressed_keys.extend(gesture.keys.iter().copied());
+        self.suppressed_sustain |= gesture.sustain;
+        Some(gesture)
+    }
+
+    fn holds(&self, gesture: &GestureConfig) -> bool {
+        gesture.keys.iter().all(|key| self.held_keys.contains(key))
+    }
+}
+
+/// Remove the key presses that led up to the gesture from the end of a recording.
+///
+/// The event completing the gesture is not part of `events`, so its key is left alone.
+pub fn strip_gesture(
+    events: &mut Vec<RecordEvent>,
+    gesture: &GestureConfig,
+    completed_by: &MidiEvent,
+) {
+    let completing_key = match *completed_by {
+        MidiEvent::NoteOn { note, .. } => Some(note),
+        _ => None,
+    };
+    for key in gesture.keys.iter() {
+        if completing_key == Some(*key) {
+            continue;
+        }
+        let pressed = events.iter().rposition(
+            |event| matches!(event.payload, MidiEvent::NoteOn { note, .. } if note == *key),
+        );
+        if let Some(index) = pressed {
+            events.remove(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GestureAction;
+
+    fn note_on(note: u8) -> MidiEvent {
+        MidiEvent::NoteOn {
+            channel: 0,
+            note,
+            velocity: 64,
+        }
+    }
+
+    fn note_off(note: u8) -> MidiEvent {
+        MidiEvent::NoteOff { channel: 0, note }
+    }
+
+    fn sustain(value: i32) -> MidiEvent {
+        MidiEvent::ControlChange {
+            channel: 0,
+            controller: SUSTAIN_CONTROLLER,
+            value,
+        }
+    }
+
+    fn detector() -> GestureDetector {
+        GestureDetector::new(vec![
+            GestureConfig {
+                keys: vec![21, 22],
+                sustain: false,
+                action: GestureAction::Split,
+            },
+            GestureConfig {
+                keys: vec![21],
+                sustain: true,
+                action: GestureAction::Favourite,
+            },
+            GestureConfig {
+                keys: vec![],
+                sustain: false,
+                action: GestureAction::Discard,
+            },
+        ])
+    }
+
+    #[test]
+    fn detect_keys() {
+        let mut detector = detector();
+        assert!(detector.detect(&note_on(21)).is_none());
+        assert!(detector.detect(&note_on(60)).is_none());
+        let gesture = detector.detect(&note_on(22)).unwrap();
+        assert_eq!(gesture.action, GestureAction::Split);
+
+        // Releasing the gesture keys is suppressed, other keys are not
+        assert!(detector.suppress(&note_off(22)));
+        assert!(!detector.suppress(&note_off(60)));
+        assert!(detector.suppress(&note_off(21)));
+        assert!(!detector.suppress(&note_off(21)));
+    }
+
+    #[test]
+    fn detect_keys_with_sustain() {
+        let mut detector = detector();
+        // Holding the sustain pedal already doesn't count
+        assert!(detector.detect(&sustain(127)).is_none());
+        assert!(detector.detect(&note_on(21)).is_none());
+        assert!(detector.detect(&sustain(127)).is_none());
+        assert!(detector.detect(&sustain(0)).is_none());
+
+        let gesture = detector.detect(&sustain(100)).unwrap();
+        assert_eq!(gesture.action, GestureAction::Favourite);
+        // The pedal is suppressed until it is released
+        assert!(detector.suppress(&sustain(90)));
+        assert!(detector.suppress(&sustain(0)));
+        assert!(!detector.suppress(&sustain(127)));
+        assert!(detector.suppress(&note_off(21)));
+    }
+
+    /// Feed the events to the detector the way the recorder does, returning what gets recorded and
+    /// the gesture that ended the recording.
+    fn record(
+        detector: &mut GestureDetector,
+        played: &[MidiEvent],
+    ) -> (Vec<MidiEvent>, Option<GestureConfig>) {
+        let mut events = Vec::new();
+        for (timestamp, payload) in played.iter().enumerate() {
+            if detector.suppress(payload) {
+                continue;
+            }
+            if let Some(gesture) = detector.detect(payload) {
+                strip_gesture(&mut events, &gesture, payload);
+                let events = events.into_iter().map(|event| event.payload).collect();
+                return (events, Some(gesture));
+            }
+            events.push(RecordEvent {
+                timestamp: timestamp as u64,
+                payload: payload.clone(),
+            });
+        }
+        (
+            events.into_iter().map(|event| event.payload).collect(),
+            None,
+        )
+    }
+
+    #[test]
+    fn strip_gesture_keys() {
+        let mut detector = detector();
+        // Key 22 was part of the music before it completed the gesture
+        let (events, gesture) = record(
+            &mut detector,
+            &[
+                note_on(22),
+                note_off(22),
+                note_on(21),
+                note_on(60),
+                note_off(60),
+                note_on(22),
+            ],
+        );
+        assert_eq!(gesture.unwrap().action, GestureAction::Split);
+        assert_eq!(
+            events,
+            [note_on(22), note_off(22), note_on(60), note_off(60)]
+        );
+    }
+
+    #[test]
+    fn strip_gesture_keys_with_sustain() {
+        let mut detector = detector();
+        let (events, gesture) = record(
+            &mut detector,
+            &[
+                note_on(21),
+                note_off(21),
+                note_on(60),
+                note_on(21),
+                sustain(127),
+            ],
+        );
+        assert_eq!(gesture.unwrap().action, GestureAction::Favourite);
+        assert_eq!(events, [note_on(21), note_off(21), note_on(60)]);
+    }
+}
@@ -1,8 +1,9 @@
-use std::convert::Infallible;
+use std::{convert::Infallible, time::Duration};
 
 use axum::{
-    extract::Path,
-    http::{HeaderValue, StatusCode},
+    body::StreamBody,
+    extract::{Multipart, Path, Query},
+    http::{header, HeaderValue, StatusCode},
     response::{
         sse::{Event, KeepAlive},
         IntoResponse, Sse,
@@ -10,45 +11,77 @@ use axum::{
     Extension, Json,
 };
 use chrono::{DateTime, Utc};
+use color_eyre::eyre::ErrReport;
 use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast;
 use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
 use tracing::error;
 
 use crate::{
-    app::{App, StateChange},
-    store::{RecordingId, RecordingInfo},
+    app::{App, DeviceStatus, InvalidRequest, QueueStatus, StateChange},
+    export, import,
+    player::{EntryId, PlayLoop, PlayOptions, QueueEntry, QueueSettings, RepeatMode},
+    store::{
+        Piece, PieceId, RecordingError, RecordingId, RecordingInfo, RecordingQuery, RecordingSort,
+        SortOrder, StopReason, DEFAULT_PAGE_SIZE,
+    },
 };
 
-#[derive(Serialize, Deserialize)]
-pub struct DeviceObject {
-    id: String,
-    description: String,
-}
-
-// TODO: reinstate /devices endpoint
-// /// Return list of devices
-// pub async fn devices(app: Extension<App>) -> Json<Vec<DeviceObject>> {
-//     let result = app
-//         .state()
-//         .devices
-//         .iter()
-//         .map(|(device, info)| DeviceObject {
-//             id: device.id(),
-//             description: format!("{} ({})", info.client_name, info.port_name),
-//         })
-//         .collect();
-
-//     Json(result)
-// }
+#[derive(Serialize)]
+pub struct ClientDevice {
+    pub id: String,
+    pub client_name: String,
+    pub port_name: String,
+    pub connected: bool,
+    pub changed_at: DateTime<Utc>,
+    /// Whether the device is being recorded from
+    pub listening: bool,
+    /// Whether a song is being recorded on the device right now
+    pub recording: bool,
+}
+
+impl From<DeviceStatus> for ClientDevice {
+    fn from(status: DeviceStatus) -> Self {
+        ClientDevice {
+            id: status.device.id(),
+            client_name: status.info.client_name,
+            port_name: status.info.port_name,
+            connected: status.connected,
+            changed_at: status.changed_at,
+            listening: status.listening,
+            recording: status.recording,
+        }
+    }
+}
+
+/// Return list of all MIDI devices seen since the daemon started
+pub async fn devices(app: Extension<App>) -> Json<Vec<ClientDevice>> {
+    let result = app
+        .devices()
+        .await
+        .into_iter()
+        .map(ClientDevice::from)
+        .collect();
+    Json(result)
+}
 
 #[derive(Serialize)]
 pub struct ClientRecordingInfo {
     pub id: RecordingId,
     pub name: String,
+    pub notes: String,
     pub created_at: DateTime<Utc>,
     pub length_seconds: f64,
     pub note_count: u32,
+    pub piece_id: Option<PieceId>,
+    pub tags: Vec<String>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub recovered: bool,
+    pub stop_reason: Option<StopReason>,
+    pub short: bool,
+    pub device_client_name: Option<String>,
+    pub device_port_name: Option<String>,
 }
 
 impl From<RecordingInfo> for ClientRecordingInfo {
@@ -56,27 +89,132 @@ impl From<RecordingInfo> for ClientRecordingInfo {
         ClientRecordingInfo {
             id: entry.id,
             name: entry.name.clone(),
+            notes: entry.notes.clone(),
             created_at: entry.created_at,
             length_seconds: entry.length_seconds,
             note_count: entry.note_count,
+            piece_id: entry.piece_id,
+            tags: entry.tags.0,
+            deleted_at: entry.deleted_at,
+            recovered: entry.recovered,
+            stop_reason: entry.stop_reason,
+            short: entry.short,
+            device_client_name: entry.device_client_name,
+            device_port_name: entry.device_port_name,
         }
     }
 }
 
-/// Return list of recordings
-pub async fn get_recordings(app: Extension<App>) -> Json<Vec<ClientRecordingInfo>> {
-    let songs = app.query_recordings().await.map_or_else(
-        |err| {
-            error!("Failed to list songs: {}", err);
-            vec![]
-        },
-        |songs| songs.into_iter().map(ClientRecordingInfo::from).collect(),
-    );
+#[derive(Deserialize)]
+pub struct RecordingsParams {
+    name: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    min_length_seconds: Option<f64>,
+    max_length_seconds: Option<f64>,
+    min_note_count: Option<u32>,
+    max_note_count: Option<u32>,
+    piece_id: Option<PieceId>,
+    /// Comma-separated list of tags that recordings must all have
+    tags: Option<String>,
+    /// Also list recordings that were flagged as short
+    #[serde(default)]
+    include_short: bool,
+    device_client_name: Option<String>,
+    device_port_name: Option<String>,
+    #[serde(default)]
+    sort: RecordingSort,
+    #[serde(default)]
+    order: SortOrder,
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
 
-    Json(songs)
+impl From<RecordingsParams> for RecordingQuery {
+    fn from(params: RecordingsParams) -> Self {
+        RecordingQuery {
+            name: params.name,
+            created_after: params.created_after,
+            created_before: params.created_before,
+            min_length_seconds: params.min_length_seconds,
+            max_length_seconds: params.max_length_seconds,
+            min_note_count: params.min_note_count,
+            max_note_count: params.max_note_count,
+            piece_id: params.piece_id,
+            tags: params.tags.map_or_else(Vec::new, |tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(String::from)
+                    .collect()
+            }),
+            include_short: params.include_short,
+            device_client_name: params.device_client_name,
+            device_port_name: params.device_port_name,
+            sort: params.sort,
+            order: params.order,
+            cursor: params.cursor,
+            limit: params.limit,
+        }
+    }
 }
 
-/// Delete a recording
+#[derive(Serialize)]
+pub struct RecordingsPage {
+    pub recordings: Vec<ClientRecordingInfo>,
+    pub next_cursor: Option<String>,
+}
+
+/// Return a page of recordings matching the filters given as query parameters
+pub async fn get_recordings(
+    app: Extension<App>,
+    Query(params): Query<RecordingsParams>,
+) -> Result<Json<RecordingsPage>, AppError> {
+    let page = app.query_recordings(params.into()).await.map_err(|err| {
+        error!("Failed to list songs: {}", err);
+        err
+    })?;
+
+    Ok(Json(RecordingsPage {
+        recordings: page
+            .recordings
+            .into_iter()
+            .map(ClientRecordingInfo::from)
+            .collect(),
+        next_cursor: page.next_cursor,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: String,
+    /// Also find recordings that were flagged as short
+    #[serde(default)]
+    include_short: bool,
+    limit: Option<u32>,
+}
+
+/// Full-text search over names and notes of the recordings, best matches first
+pub async fn search_recordings(
+    app: Extension<App>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<Vec<ClientRecordingInfo>>, AppError> {
+    let recordings = app
+        .search_recordings(
+            &params.q,
+            params.include_short,
+            params.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+        )
+        .await?;
+    Ok(Json(
+        recordings
+            .into_iter()
+            .map(ClientRecordingInfo::from)
+            .collect(),
+    ))
+}
+
+/// Delete a recording (by moving it to the trash)
 pub async fn delete_recording(
     app: Extension<App>,
     Path((recording_id,)): Path<(RecordingId,)>,
@@ -85,14 +223,192 @@ pub async fn delete_recording(
     Ok(Json(()))
 }
 
+/// Download a recording as standard MIDI file
+pub async fn get_recording_midi(
+    app: Extension<App>,
+    Path((recording_id,)): Path<(RecordingId,)>,
+) -> Result<impl IntoResponse, AppError> {
+    let (rec, data) = app.get_recording_midi(recording_id).await?;
+    let disposition = content_disposition_attachment(&rec.midi_file_name());
+    Ok((
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("audio/midi")),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        data,
+    ))
+}
+
+/// Build a `Content-Disposition` header for downloading a file.
+///
+/// Header values can only contain ASCII, so the actual name is passed percent-encoded in
+/// `filename*` (RFC 6266), with an ASCII-only approximation as fallback for older clients.
+fn content_disposition_attachment(file_name: &str) -> HeaderValue {
+    let fallback = file_name
+        .chars()
+        .map(|c| {
+            if c == ' ' || (c.is_ascii_graphic() && c != '"' && c != '\\') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let encoded = file_name
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect::<String>();
+    HeaderValue::from_str(&format!(
+        "attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}"
+    ))
+    .expect("header value only contains visible ASCII")
+}
+
+/// Import MIDI files as new recordings.
+///
+/// Expects a multipart form with one or more `file` fields containing standard MIDI files. The
+/// creation time of the recordings can be given in an (RFC 3339) `created_at` field, otherwise it
+/// is derived from the file name if possible, or the current time is used. Either all files are
+/// imported, or none if any of them is invalid.
+pub async fn import_recordings(
+    app: Extension<App>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<ClientRecordingInfo>>, AppError> {
+    let mut created_at = None;
+    let mut files = Vec::new();
+
+    while let Some(field) = multipart.next_field().await.map_err(ErrReport::new)? {
+        match field.name() {
+            Some("created_at") => {
+                let text = field.text().await.map_err(ErrReport::new)?;
+                created_at = Some(
+                    DateTime::parse_from_rfc3339(text.trim())
+                        .map_err(|err| {
+                            InvalidRequest(format!("Invalid created_at {:?}: {}", text, err))
+                        })?
+                        .with_timezone(&Utc),
+                );
+            }
+            Some("file") => {
+                let file_name = field.file_name().unwrap_or_default().to_owned();
+                let data = field.bytes().await.map_err(ErrReport::new)?;
+                files.push((file_name, data));
+            }
+            _ => continue,
+        }
+    }
+
+    // Validate everything before importing anything
+    let mut recordings = Vec::with_capacity(files.len());
+    for (file_name, data) in files {
+        let events = import::normalize_midi(&data)
+            .map_err(|err| InvalidRequest(format!("Failed to import {:?}: {}", file_name, err)))?;
+        let created_at = created_at
+            .or_else(|| import::created_at_from_file_name(&file_name))
+            .unwrap_or_else(Utc::now);
+        recordings.push((events, created_at));
+    }
+
+    let imported = app.import_recordings(recordings).await?;
+    Ok(Json(
+        imported
+            .into_iter()
+            .map(ClientRecordingInfo::from)
+            .collect(),
+    ))
+}
+
+/// Download all recordings as ZIP archive of MIDI files, with a manifest containing the metadata
+pub async fn export_zip(app: Extension<App>) -> impl IntoResponse {
+    let store = app.recording_store().await;
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+
+    tokio::spawn(async move {
+        if let Err(err) = export::export_library(&store, writer).await {
+            error!("Failed to export library: {}", err);
+        }
+    });
+
+    let file_name = format!(
+        "autorec-{}.zip",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    );
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/zip"),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition_attachment(&file_name),
+            ),
+        ],
+        StreamBody::new(ReaderStream::new(reader)),
+    )
+}
+
+/// Return list of recordings in the trash
+pub async fn get_trash(app: Extension<App>) -> Result<Json<Vec<ClientRecordingInfo>>, AppError> {
+    let recordings = app.query_trash().await?;
+    Ok(Json(
+        recordings
+            .into_iter()
+            .map(ClientRecordingInfo::from)
+            .collect(),
+    ))
+}
+
+/// Restore a recording from the trash
+pub async fn restore_recording(
+    app: Extension<App>,
+    Path((recording_id,)): Path<(RecordingId,)>,
+) -> Result<Json<ClientRecordingInfo>, AppError> {
+    let rec = app.restore_recording(recording_id).await?;
+    Ok(Json(rec.into()))
+}
+
+#[derive(Serialize)]
+pub struct ClientBackup {
+    pub file_name: String,
+    pub created_at: DateTime<Utc>,
+    pub size: u64,
+}
+
+/// Back up the database right away
+pub async fn create_backup(app: Extension<App>) -> Result<Json<ClientBackup>, AppError> {
+    let backup = app.create_backup().await.map_err(|err| {
+        error!("Failed to back up database: {}", err);
+        err
+    })?;
+    Ok(Json(ClientBackup {
+        file_name: backup
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        created_at: backup.created_at,
+        size: backup.size,
+    }))
+}
+
 #[derive(Deserialize)]
 pub struct RecUpdate {
-    pub name: String,
+    pub name: Option<String>,
+    pub notes: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct NameClassification {
     pub name: String,
+    pub piece_id: Option<PieceId>,
     pub similarity: f64,
 }
 
@@ -102,7 +418,9 @@ pub async fn update_recording(
     Path((recording_id,)): Path<(RecordingId,)>,
     Json(update): Json<RecUpdate>,
 ) -> Result<Json<ClientRecordingInfo>, AppError> {
-    let rec = app.rename_recording(recording_id, update.name).await?;
+    let rec = app
+        .update_recording(recording_id, update.name, update.notes)
+        .await?;
     Ok(Json(rec.into()))
 }
 
@@ -115,32 +433,207 @@ pub async fn classify_recording(
     Ok(Json(
         guesses
             .into_iter()
-            .map(|(name, similarity)| NameClassification { name, similarity })
+            .map(|guess| NameClassification {
+                name: guess.name,
+                piece_id: guess.piece_id,
+                similarity: guess.similarity,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct PieceAssignment {
+    pub piece_id: Option<PieceId>,
+}
+
+/// Assign a recording to a piece, or unassign it when `piece_id` is `null`
+pub async fn assign_recording_piece(
+    app: Extension<App>,
+    Path((recording_id,)): Path<(RecordingId,)>,
+    Json(assignment): Json<PieceAssignment>,
+) -> Result<Json<ClientRecordingInfo>, AppError> {
+    let rec = app
+        .assign_recording_to_piece(recording_id, assignment.piece_id)
+        .await?;
+    Ok(Json(rec.into()))
+}
+
+/// Tag a recording
+pub async fn add_recording_tag(
+    app: Extension<App>,
+    Path((recording_id, tag)): Path<(RecordingId, String)>,
+) -> Result<Json<ClientRecordingInfo>, AppError> {
+    let rec = app.add_recording_tag(recording_id, &tag).await?;
+    Ok(Json(rec.into()))
+}
+
+/// Remove a tag from a recording
+pub async fn remove_recording_tag(
+    app: Extension<App>,
+    Path((recording_id, tag)): Path<(RecordingId, String)>,
+) -> Result<Json<ClientRecordingInfo>, AppError> {
+    let rec = app.remove_recording_tag(recording_id, &tag).await?;
+    Ok(Json(rec.into()))
+}
+
+#[derive(Serialize)]
+pub struct ClientTag {
+    pub name: String,
+    pub recording_count: u32,
+}
+
+/// Return list of tags in use, with the number of recordings having that tag
+pub async fn get_tags(app: Extension<App>) -> Result<Json<Vec<ClientTag>>, AppError> {
+    let tags = app.query_tags().await?;
+    Ok(Json(
+        tags.into_iter()
+            .map(|tag| ClientTag {
+                name: tag.name,
+                recording_count: tag.recording_count,
+            })
             .collect(),
     ))
 }
 
+#[derive(Serialize)]
+pub struct ClientPiece {
+    pub id: PieceId,
+    pub title: String,
+    pub composer: String,
+    pub catalogue_number: Option<String>,
+}
+
+impl From<Piece> for ClientPiece {
+    fn from(piece: Piece) -> Self {
+        ClientPiece {
+            id: piece.id,
+            title: piece.title,
+            composer: piece.composer,
+            catalogue_number: piece.catalogue_number,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PieceUpdate {
+    pub title: String,
+    #[serde(default)]
+    pub composer: String,
+    pub catalogue_number: Option<String>,
+}
+
+/// Return list of pieces
+pub async fn get_pieces(app: Extension<App>) -> Result<Json<Vec<ClientPiece>>, AppError> {
+    let pieces = app.query_pieces().await?;
+    Ok(Json(pieces.into_iter().map(ClientPiece::from).collect()))
+}
+
+/// Return a single piece
+pub async fn get_piece(
+    app: Extension<App>,
+    Path((piece_id,)): Path<(PieceId,)>,
+) -> Result<Json<ClientPiece>, AppError> {
+    let piece = app.get_piece(piece_id).await?;
+    Ok(Json(piece.into()))
+}
+
+/// Create a new piece
+pub async fn create_piece(
+    app: Extension<App>,
+    Json(piece): Json<PieceUpdate>,
+) -> Result<Json<ClientPiece>, AppError> {
+    let piece = app
+        .create_piece(piece.title, piece.composer, piece.catalogue_number)
+        .await?;
+    Ok(Json(piece.into()))
+}
+
+/// Update a piece
+pub async fn update_piece(
+    app: Extension<App>,
+    Path((piece_id,)): Path<(PieceId,)>,
+    Json(update): Json<PieceUpdate>,
+) -> Result<Json<ClientPiece>, AppError> {
+    let piece = app
+        .update_piece(
+            piece_id,
+            update.title,
+            update.composer,
+            update.catalogue_number,
+        )
+        .await?;
+    Ok(Json(piece.into()))
+}
+
+/// Delete a piece
+pub async fn delete_piece(
+    app: Extension<App>,
+    Path((piece_id,)): Path<(PieceId,)>,
+) -> Result<Json<()>, AppError> {
+    app.delete_piece(piece_id).await?;
+    Ok(Json(()))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PlayRequest {
     id: RecordingId,
+    /// Id (`client:port`) or client name of the device to play on, defaults to the device the
+    /// recording was made on
+    #[serde(default)]
+    device: Option<String>,
+    /// Playback speed relative to the recording, e.g. 0.7 for playing at 70% speed
+    #[serde(default)]
+    tempo_factor: Option<f64>,
+    /// Semitones to shift all notes by
+    #[serde(default)]
+    transpose: Option<i8>,
+    /// Start of a section to play repeatedly, requires `loop_end_seconds`
+    #[serde(default)]
+    loop_start_seconds: Option<f64>,
+    /// End of the section to play repeatedly, requires `loop_start_seconds`
+    #[serde(default)]
+    loop_end_seconds: Option<f64>,
+    /// How often the section is played, endlessly if not given
+    #[serde(default)]
+    loop_repetitions: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct AppError {
     message: String,
+    #[serde(skip, default = "internal_server_error")]
+    status: StatusCode,
+}
+
+fn internal_server_error() -> StatusCode {
+    StatusCode::INTERNAL_SERVER_ERROR
 }
 
 impl From<color_eyre::eyre::ErrReport> for AppError {
     fn from(err: color_eyre::eyre::ErrReport) -> Self {
+        let status = match err.downcast_ref::<RecordingError>() {
+            Some(RecordingError::NotFound(_)) => StatusCode::NOT_FOUND,
+            Some(RecordingError::Trashed(_)) => StatusCode::CONFLICT,
+            None if err.is::<InvalidRequest>() => StatusCode::BAD_REQUEST,
+            None => internal_server_error(),
+        };
         AppError {
             message: err.to_string(),
+            status,
         }
     }
 }
 
+impl From<InvalidRequest> for AppError {
+    fn from(err: InvalidRequest) -> Self {
+        ErrReport::new(err).into()
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, self.message).into_response()
+        (self.status, self.message).into_response()
     }
 }
 
@@ -149,7 +642,33 @@ pub async fn play(
     app: Extension<App>,
     Json(request): Json<PlayRequest>,
 ) -> Result<Json<()>, AppError> {
-    app.play_recording(request.id).await?;
+    let looping = match (request.loop_start_seconds, request.loop_end_seconds) {
+        (Some(start), Some(end)) => {
+            for seconds in [start, end] {
+                if !(seconds.is_finite() && seconds >= 0.0) {
+                    return Err(InvalidRequest(format!("Invalid loop position {}", seconds)).into());
+                }
+            }
+            Some(PlayLoop {
+                start: Duration::from_secs_f64(start),
+                end: Duration::from_secs_f64(end),
+                repetitions: request.loop_repetitions,
+            })
+        }
+        (None, None) if request.loop_repetitions.is_none() => None,
+        _ => {
+            let message = "A loop needs both a start and an end".to_owned();
+            return Err(InvalidRequest(message).into());
+        }
+    };
+    let defaults = PlayOptions::default();
+    let options = PlayOptions {
+        tempo_factor: request.tempo_factor.unwrap_or(defaults.tempo_factor),
+        transpose: request.transpose.unwrap_or(defaults.transpose),
+        looping,
+    };
+    app.play_recording(request.id, request.device.as_deref(), options)
+        .await?;
     Ok(Json(()))
 }
 
@@ -159,40 +678,346 @@ pub async fn stop(app: Extension<App>, Json(()): Json<()>) -> Json<()> {
     Json(())
 }
 
-pub async fn play_status(app: Extension<App>) -> Json<Option<RecordingId>> {
-    Json(app.playing_recording().await)
+pub async fn pause(app: Extension<App>, Json(()): Json<()>) -> Result<Json<()>, AppError> {
+    app.pause_playing().await?;
+    Ok(Json(()))
+}
+
+pub async fn resume(app: Extension<App>, Json(()): Json<()>) -> Result<Json<()>, AppError> {
+    app.resume_playing().await?;
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+pub struct SeekRequest {
+    /// Time from the beginning of the recording
+    position_seconds: f64,
+}
+
+pub async fn seek(
+    app: Extension<App>,
+    Json(request): Json<SeekRequest>,
+) -> Result<Json<()>, AppError> {
+    if !(request.position_seconds.is_finite() && request.position_seconds >= 0.0) {
+        let message = format!("Invalid position {}", request.position_seconds);
+        return Err(InvalidRequest(message).into());
+    }
+    app.seek_playing(Duration::from_secs_f64(request.position_seconds))
+        .await?;
+    Ok(Json(()))
+}
+
+#[derive(Serialize)]
+pub struct PlayStatus {
+    recording: RecordingId,
+    paused: bool,
+    position_seconds: f64,
+    tempo_factor: f64,
+    transpose: i8,
+    loop_start_seconds: Option<f64>,
+    loop_end_seconds: Option<f64>,
+    loop_repetitions: Option<u32>,
+}
+
+/// What is being played, `null` if nothing
+pub async fn play_status(app: Extension<App>) -> Json<Option<PlayStatus>> {
+    Json(app.play_status().await.map(|status| {
+        let looping = status.options.looping;
+        PlayStatus {
+            recording: status.current,
+            paused: status.paused,
+            position_seconds: status.position.as_secs_f64(),
+            tempo_factor: status.options.tempo_factor,
+            transpose: status.options.transpose,
+            loop_start_seconds: looping.map(|looping| looping.start.as_secs_f64()),
+            loop_end_seconds: looping.map(|looping| looping.end.as_secs_f64()),
+            loop_repetitions: looping.and_then(|looping| looping.repetitions),
+        }
+    }))
+}
+
+#[derive(Serialize)]
+pub struct ClientQueueEntry {
+    id: EntryId,
+    recording: RecordingId,
+}
+
+impl From<QueueEntry<RecordingId>> for ClientQueueEntry {
+    fn from(entry: QueueEntry<RecordingId>) -> Self {
+        Self {
+            id: entry.id,
+            recording: entry.item,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ClientQueue {
+    /// Entry being played, `null` if nothing is playing (or while waiting for the gap)
+    current: Option<ClientQueueEntry>,
+    /// Entries to be played next
+    entries: Vec<ClientQueueEntry>,
+    repeat: RepeatMode,
+    shuffle: bool,
+    gap_seconds: f64,
+}
+
+impl From<QueueStatus> for ClientQueue {
+    fn from(status: QueueStatus) -> Self {
+        Self {
+            current: status.current.map(ClientQueueEntry::from),
+            entries: status
+                .entries
+                .into_iter()
+                .map(ClientQueueEntry::from)
+                .collect(),
+            repeat: status.settings.repeat,
+            shuffle: status.settings.shuffle,
+            gap_seconds: status.settings.gap.as_secs_f64(),
+        }
+    }
+}
+
+pub async fn get_queue(app: Extension<App>) -> Json<ClientQueue> {
+    Json(ClientQueue::from(app.queue().await))
+}
+
+#[derive(Deserialize)]
+pub struct EnqueueRequest {
+    id: RecordingId,
+}
+
+#[derive(Serialize)]
+pub struct EnqueueResponse {
+    id: EntryId,
+}
+
+/// Add a recording to the end of the queue, playback starts if nothing is playing
+pub async fn enqueue(
+    app: Extension<App>,
+    Json(request): Json<EnqueueRequest>,
+) -> Result<Json<EnqueueResponse>, AppError> {
+    let id = app.enqueue_recording(request.id).await?;
+    Ok(Json(EnqueueResponse { id }))
+}
+
+pub async fn clear_queue(app: Extension<App>) -> Json<()> {
+    app.clear_queue().await;
+    Json(())
+}
+
+pub async fn remove_queue_entry(
+    app: Extension<App>,
+    Path((entry_id,)): Path<(EntryId,)>,
+) -> Result<Json<()>, AppError> {
+    app.remove_queue_entry(entry_id).await?;
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+pub struct MoveQueueEntryRequest {
+    /// New index of the entry within the queue, moved to the end if out of range
+    index: usize,
+}
+
+pub async fn move_queue_entry(
+    app: Extension<App>,
+    Path((entry_id,)): Path<(EntryId,)>,
+    Json(request): Json<MoveQueueEntryRequest>,
+) -> Result<Json<()>, AppError> {
+    app.move_queue_entry(entry_id, request.index).await?;
+    Ok(Json(()))
+}
+
+/// Stop the current entry and continue with the next one right away
+pub async fn skip(app: Extension<App>, Json(()): Json<()>) -> Json<()> {
+    app.skip_playing().await;
+    Json(())
+}
+
+#[derive(Deserialize)]
+pub struct QueueSettingsRequest {
+    repeat: RepeatMode,
+    shuffle: bool,
+    /// Pause between two entries
+    gap_seconds: f64,
+}
+
+pub async fn update_queue_settings(
+    app: Extension<App>,
+    Json(request): Json<QueueSettingsRequest>,
+) -> Result<Json<()>, AppError> {
+    if !(request.gap_seconds.is_finite() && request.gap_seconds >= 0.0) {
+        return Err(InvalidRequest(format!("Invalid gap {}", request.gap_seconds)).into());
+    }
+    app.set_queue_settings(QueueSettings {
+        repeat: request.repeat,
+        shuffle: request.shuffle,
+        gap: Duration::from_secs_f64(request.gap_seconds),
+    })
+    .await;
+    Ok(Json(()))
 }
 
 #[derive(Serialize)]
 #[serde(tag = "type")]
 pub enum UpdateEvent {
-    RecordBegin,
-    RecordEnd { recording: ClientRecordingInfo },
-    RecordDelete { recording_id: RecordingId },
-    RecordError { message: String },
-    RecordUpdate { recording: ClientRecordingInfo },
-    PlayBegin { recording: RecordingId },
+    DeviceConnect {
+        device: String,
+        client_name: String,
+        port_name: String,
+    },
+    DeviceDisconnect {
+        device: String,
+    },
+    ListenBegin {
+        device: String,
+        client_name: String,
+        port_name: String,
+    },
+    ListenEnd {
+        device: String,
+    },
+    RecordBegin {
+        device: String,
+    },
+    RecordEnd {
+        device: String,
+        recording: ClientRecordingInfo,
+    },
+    RecordDelete {
+        recording_id: RecordingId,
+    },
+    RecordRestore {
+        recording: ClientRecordingInfo,
+    },
+    RecordDiscard {
+        device: String,
+    },
+    RecordShort {
+        device: String,
+        note_count: u32,
+        distinct_pitches: u32,
+        length_seconds: f64,
+        recording: Option<ClientRecordingInfo>,
+    },
+    RecordError {
+        device: String,
+        message: String,
+    },
+    RecordImport {
+        recording: ClientRecordingInfo,
+    },
+    RecordUpdate {
+        recording: ClientRecordingInfo,
+    },
+    PieceUpdate {
+        piece: ClientPiece,
+    },
+    PieceDelete {
+        piece_id: PieceId,
+    },
+    PlayBegin {
+        recording: RecordingId,
+    },
     PlayEnd,
+    PlayPause {
+        recording: RecordingId,
+    },
+    PlayResume {
+        recording: RecordingId,
+    },
+    PlaySeek {
+        recording: RecordingId,
+        position_seconds: f64,
+    },
+    QueueUpdate {
+        entries: Vec<ClientQueueEntry>,
+        repeat: RepeatMode,
+        shuffle: bool,
+        gap_seconds: f64,
+    },
 }
 
 impl UpdateEvent {
     pub fn from_state_change(change: StateChange) -> Option<UpdateEvent> {
         match change {
-            StateChange::ListenBegin { .. } => None,
-            StateChange::ListenEnd => None,
-            StateChange::RecordBegin => Some(UpdateEvent::RecordBegin),
-            StateChange::RecordEnd { recording } => Some(UpdateEvent::RecordEnd {
+            StateChange::DeviceConnect { device, info } => Some(UpdateEvent::DeviceConnect {
+                device: device.id(),
+                client_name: info.client_name,
+                port_name: info.port_name,
+            }),
+            StateChange::DeviceDisconnect { device } => Some(UpdateEvent::DeviceDisconnect {
+                device: device.id(),
+            }),
+            StateChange::ListenBegin { device, info } => Some(UpdateEvent::ListenBegin {
+                device: device.id(),
+                client_name: info.client_name,
+                port_name: info.port_name,
+            }),
+            StateChange::ListenEnd { device } => Some(UpdateEvent::ListenEnd {
+                device: device.id(),
+            }),
+            StateChange::RecordBegin { device } => Some(UpdateEvent::RecordBegin {
+                device: device.id(),
+            }),
+            StateChange::RecordEnd { device, recording } => Some(UpdateEvent::RecordEnd {
+                device: device.id(),
                 recording: ClientRecordingInfo::from(recording),
             }),
             StateChange::RecordUpdate { recording } => Some(UpdateEvent::RecordUpdate {
                 recording: ClientRecordingInfo::from(recording),
             }),
-            StateChange::RecordError { message } => Some(UpdateEvent::RecordError { message }),
+            StateChange::RecordDiscard { device } => Some(UpdateEvent::RecordDiscard {
+                device: device.id(),
+            }),
+            StateChange::RecordShort {
+                device,
+                note_count,
+                distinct_pitches,
+                length_seconds,
+                recording,
+            } => Some(UpdateEvent::RecordShort {
+                device: device.id(),
+                note_count,
+                distinct_pitches,
+                length_seconds,
+                recording: recording.map(ClientRecordingInfo::from),
+            }),
+            StateChange::RecordError { device, message } => Some(UpdateEvent::RecordError {
+                device: device.id(),
+                message,
+            }),
+            StateChange::RecordImport { recording } => Some(UpdateEvent::RecordImport {
+                recording: ClientRecordingInfo::from(recording),
+            }),
             StateChange::PlayBegin { recording } => Some(UpdateEvent::PlayBegin { recording }),
             StateChange::PlayEnd => Some(UpdateEvent::PlayEnd),
+            StateChange::PlayPause { recording } => Some(UpdateEvent::PlayPause { recording }),
+            StateChange::PlayResume { recording } => Some(UpdateEvent::PlayResume { recording }),
+            StateChange::PlaySeek {
+                recording,
+                position,
+            } => Some(UpdateEvent::PlaySeek {
+                recording,
+                position_seconds: position.as_secs_f64(),
+            }),
+            StateChange::QueueUpdate { entries, settings } => Some(UpdateEvent::QueueUpdate {
+                entries: entries.into_iter().map(ClientQueueEntry::from).collect(),
+                repeat: settings.repeat,
+                shuffle: settings.shuffle,
+                gap_seconds: settings.gap.as_secs_f64(),
+            }),
             StateChange::RecordDelete { recording_id } => {
                 Some(UpdateEvent::RecordDelete { recording_id })
             }
+            StateChange::RecordRestore { recording } => Some(UpdateEvent::RecordRestore {
+                recording: ClientRecordingInfo::from(recording),
+            }),
+            StateChange::PieceUpdate { piece } => Some(UpdateEvent::PieceUpdate {
+                piece: ClientPiece::from(piece),
+            }),
+            StateChange::PieceDelete { piece_id } => Some(UpdateEvent::PieceDelete { piece_id }),
         }
     }
 }
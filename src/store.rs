@@ -1,15 +1,21 @@
-use std::path::Path;
+use std::{os::unix::io::AsRawFd, path::Path};
 
-use chrono::{Local, NaiveDateTime, TimeZone, Utc};
-use color_eyre::eyre::bail;
+use chrono::{DateTime, Local, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
 use serde::{Deserialize, Serialize};
 use sqlx::{
-    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
-    Sqlite, SqlitePool, Transaction,
+    sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteTypeInfo, SqliteValueRef,
+    },
+    FromRow, QueryBuilder, Sqlite, SqlitePool, Transaction,
 };
 use tracing::{debug, info, warn};
 
-use crate::midi::{RECORDING_PPQ, RECORDING_TEMPO, RECORDING_BPM};
+use crate::midi::{DeviceInfo, RECORDING_PPQ, RECORDING_TEMPO, RECORDING_BPM};
 
 #[derive(
     Debug,
@@ -35,16 +41,328 @@ where
     }
 }
 
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    sqlx::Decode,
+    sqlx::Encode,
+)]
+pub struct PieceId(pub i32);
+
+impl<DB: sqlx::Database> sqlx::Type<DB> for PieceId
+where
+    i32: sqlx::Type<DB>,
+{
+    fn type_info() -> DB::TypeInfo {
+        <i32 as sqlx::Type<DB>>::type_info()
+    }
+}
+
+/// Reasons for a recording not being available, so that callers can tell them apart from other
+/// errors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordingError {
+    NotFound(RecordingId),
+    /// The recording exists, but was moved to the trash
+    Trashed(RecordingId),
+}
+
+impl std::fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordingError::NotFound(id) => write!(f, "No recording found with id {}", id.0),
+            RecordingError::Trashed(id) => write!(f, "Recording {} is in the trash", id.0),
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct RecordingInfo {
     pub id: RecordingId,
     pub name: String,
+    pub notes: String,
     pub created_at: chrono::DateTime<Utc>,
     pub length_seconds: f64,
     pub note_count: u32,
+    pub piece_id: Option<PieceId>,
+    pub tags: TagList,
+    /// When the recording was moved to the trash, `None` if it is not in the trash
+    pub deleted_at: Option<chrono::DateTime<Utc>>,
+    /// Whether the recording was interrupted, and recovered from its journal
+    pub recovered: bool,
+    /// What ended the recording, `None` for imported recordings
+    pub stop_reason: Option<StopReason>,
+    /// Whether the recording seemed accidental, such recordings are not listed by default
+    pub short: bool,
+    /// ALSA client name of the device the recording was made on, `None` for imported recordings
+    pub device_client_name: Option<String>,
+    /// ALSA port name of the device the recording was made on
+    pub device_port_name: Option<String>,
 }
 
-#[derive(Debug)]
+/// Describes what caused the end of the recording.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum StopReason {
+    /// Pianist was idle for too long
+    Idle,
+    /// Keys seemed to be pressed, but nothing happened for too long
+    StuckNotes,
+    /// Song got too long and was split
+    MaxLength,
+    /// Pianist ended the song with a gesture
+    Gesture,
+    /// Device got disconnected/turned off
+    Disconnect,
+}
+
+impl RecordingInfo {
+    /// Suggested file name when exporting the recording as MIDI file, e.g.
+    /// `Nocturne Op. 9 - 20220731-224100.mid`.
+    ///
+    /// The timestamp format is the same that was used back when recordings were stored as files.
+    pub fn midi_file_name(&self) -> String {
+        let timestamp = self
+            .created_at
+            .with_timezone(&Local)
+            .format("%Y%m%d-%H%M%S");
+        let name = self
+            .name
+            .trim()
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+                c if c.is_control() => '_',
+                c => c,
+            })
+            .collect::<String>();
+        if name.is_empty() {
+            format!("{timestamp}.mid")
+        } else {
+            format!("{name} - {timestamp}.mid")
+        }
+    }
+}
+
+/// Tags of a recording, sorted by name.
+///
+/// Tags are selected as a single text column and split up when decoding.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagList(pub Vec<String>);
+
+/// Separator of the tags in the aggregated `tags` column (ASCII unit separator).
+const TAG_SEPARATOR: char = '\x1f';
+
+impl sqlx::Type<Sqlite> for TagList {
+    fn type_info() -> SqliteTypeInfo {
+        <String as sqlx::Type<Sqlite>>::type_info()
+    }
+}
+
+impl<'r> sqlx::Decode<'r, Sqlite> for TagList {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let tags = <&str as sqlx::Decode<Sqlite>>::decode(value)?;
+        Ok(TagList(
+            tags.split(TAG_SEPARATOR)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect(),
+        ))
+    }
+}
+
+/// A tag together with the number of recordings it was assigned to.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct TagCount {
+    pub name: String,
+    pub recording_count: u32,
+}
+
+/// Aggregate numbers about the recordings in the library (excluding the trash).
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct LibraryStats {
+    pub recording_count: u32,
+    pub total_length_seconds: f64,
+    pub total_note_count: i64,
+    pub first_recording_at: Option<DateTime<Utc>>,
+    pub last_recording_at: Option<DateTime<Utc>>,
+    pub trashed_count: u32,
+    pub piece_count: u32,
+    pub tag_count: u32,
+}
+
+/// A musical piece that recordings can be assigned to.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Piece {
+    pub id: PieceId,
+    pub title: String,
+    pub composer: String,
+    /// E.g. "Op. 9 No. 2" or "BWV 846"
+    pub catalogue_number: Option<String>,
+}
+
+/// Columns that need to be selected for constructing a [`RecordingInfo`].
+const RECORDING_INFO_COLUMNS: &str = r"
+    id, name, notes, created_at, length_seconds, note_count, piece_id, deleted_at, recovered,
+    stop_reason, short, device_client_name, device_port_name,
+    COALESCE((
+        SELECT group_concat(tag_name, char(31)) FROM (
+            SELECT tags.name AS tag_name FROM recording_tags
+                JOIN tags ON tags.id = recording_tags.tag_id
+                WHERE recording_tags.recording_id = recordings.id
+                ORDER BY tags.name
+        )
+    ), '') AS tags
+";
+
+/// Number of recordings returned per page if the client didn't ask for a specific amount.
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+
+/// Upper bound on the number of recordings returned per page.
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+/// Column by which a recording listing is sorted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingSort {
+    #[default]
+    CreatedAt,
+    Name,
+    LengthSeconds,
+    NoteCount,
+}
+
+impl RecordingSort {
+    fn column(self) -> &'static str {
+        match self {
+            RecordingSort::CreatedAt => "created_at",
+            RecordingSort::Name => "name",
+            RecordingSort::LengthSeconds => "length_seconds",
+            RecordingSort::NoteCount => "note_count",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Filters and pagination parameters for listing recordings.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingQuery {
+    /// Only return recordings whose name contains this string (case-insensitive)
+    pub name: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub min_length_seconds: Option<f64>,
+    pub max_length_seconds: Option<f64>,
+    pub min_note_count: Option<u32>,
+    pub max_note_count: Option<u32>,
+    pub piece_id: Option<PieceId>,
+    /// Only return recordings that have all of these tags
+    pub tags: Vec<String>,
+    /// Also return recordings that were flagged as short
+    pub include_short: bool,
+    /// Only return recordings made on the device with this ALSA client name
+    pub device_client_name: Option<String>,
+    /// Only return recordings made on the device with this ALSA port name
+    pub device_port_name: Option<String>,
+    pub sort: RecordingSort,
+    pub order: SortOrder,
+    /// Continue after the last entry of a previous page, as returned in [`RecordingPage::next_cursor`]
+    pub cursor: Option<String>,
+    /// Maximum number of entries in the page, defaults to [`DEFAULT_PAGE_SIZE`]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordingPage {
+    pub recordings: Vec<RecordingInfo>,
+    /// Opaque cursor for requesting the next page, or `None` if this was the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Position in a sorted listing of recordings.
+///
+/// The sort key alone is not unique, hence the id is used as a tie-breaker. Cursors are encoded as
+/// `<id>:<sort key>` and are only meaningful in combination with the sort they were created for.
+#[derive(Debug, PartialEq)]
+enum CursorKey {
+    CreatedAt(DateTime<Utc>),
+    Name(String),
+    LengthSeconds(f64),
+    NoteCount(u32),
+}
+
+impl CursorKey {
+    fn of(sort: RecordingSort, rec: &RecordingInfo) -> Self {
+        match sort {
+            RecordingSort::CreatedAt => CursorKey::CreatedAt(rec.created_at),
+            RecordingSort::Name => CursorKey::Name(rec.name.clone()),
+            RecordingSort::LengthSeconds => CursorKey::LengthSeconds(rec.length_seconds),
+            RecordingSort::NoteCount => CursorKey::NoteCount(rec.note_count),
+        }
+    }
+
+    fn encode(&self, id: RecordingId) -> String {
+        let key = match self {
+            CursorKey::CreatedAt(created_at) => {
+                created_at.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+            }
+            CursorKey::Name(name) => name.clone(),
+            CursorKey::LengthSeconds(length) => length.to_string(),
+            CursorKey::NoteCount(count) => count.to_string(),
+        };
+        format!("{}:{}", id.0, key)
+    }
+
+    fn decode(sort: RecordingSort, cursor: &str) -> color_eyre::Result<(Self, RecordingId)> {
+        let invalid = || eyre!("Invalid cursor {:?}", cursor);
+
+        let (id, key) = cursor.split_once(':').ok_or_else(invalid)?;
+        let id = RecordingId(id.parse().map_err(|_| invalid())?);
+        let key = match sort {
+            RecordingSort::CreatedAt => CursorKey::CreatedAt(
+                DateTime::parse_from_rfc3339(key)
+                    .map_err(|_| invalid())?
+                    .into(),
+            ),
+            RecordingSort::Name => CursorKey::Name(key.to_owned()),
+            RecordingSort::LengthSeconds => {
+                CursorKey::LengthSeconds(key.parse().map_err(|_| invalid())?)
+            }
+            RecordingSort::NoteCount => CursorKey::NoteCount(key.parse().map_err(|_| invalid())?),
+        };
+        Ok((key, id))
+    }
+
+    fn push_bind(self, builder: &mut QueryBuilder<'_, Sqlite>) {
+        match self {
+            CursorKey::CreatedAt(created_at) => builder.push_bind(created_at),
+            CursorKey::Name(name) => builder.push_bind(name),
+            CursorKey::LengthSeconds(length) => builder.push_bind(length),
+            CursorKey::NoteCount(count) => builder.push_bind(count),
+        };
+    }
+}
+
+/// Access to the recordings database. Cloning is cheap, all clones share the same connection pool.
+#[derive(Debug, Clone)]
 pub struct RecordingStore {
     pool: SqlitePool,
 }
@@ -64,10 +382,183 @@ impl RecordingStore {
         Ok(Self { pool })
     }
 
+    /// Close all connections to the database.
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    /// Write a consistent copy of the database to `path`, which must not exist yet.
+    ///
+    /// This is safe to do while the database is in use.
+    pub async fn backup_into(&self, path: &Path) -> color_eyre::Result<()> {
+        vacuum_into(&self.pool, path).await
+    }
+
     pub async fn get_recording_infos(&self) -> color_eyre::Result<Vec<RecordingInfo>> {
-        let recordings = sqlx::query_as::<_, RecordingInfo>(
-            "SELECT id, name, created_at, length_seconds, note_count FROM recordings ORDER BY created_at DESC",
-        )
+        let recordings = sqlx::query_as::<_, RecordingInfo>(&format!(
+            "SELECT {RECORDING_INFO_COLUMNS} FROM recordings
+                WHERE deleted_at IS NULL
+                ORDER BY created_at DESC"
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(recordings)
+    }
+
+    /// Return a single page of the recordings matching the query.
+    pub async fn query_recording_infos(
+        &self,
+        query: RecordingQuery,
+    ) -> color_eyre::Result<RecordingPage> {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let column = query.sort.column();
+        let (cmp, dir) = match query.order {
+            SortOrder::Asc => (">", "ASC"),
+            SortOrder::Desc => ("<", "DESC"),
+        };
+
+        let mut builder = QueryBuilder::<Sqlite>::new(format!(
+            "SELECT {RECORDING_INFO_COLUMNS} FROM recordings WHERE deleted_at IS NULL"
+        ));
+
+        if let Some(name) = query.name {
+            builder
+                .push(" AND name LIKE '%' || ")
+                .push_bind(escape_like(&name))
+                .push(" || '%' ESCAPE '\\'");
+        }
+        if let Some(created_after) = query.created_after {
+            builder.push(" AND created_at >= ").push_bind(created_after);
+        }
+        if let Some(created_before) = query.created_before {
+            builder.push(" AND created_at < ").push_bind(created_before);
+        }
+        if let Some(min_length) = query.min_length_seconds {
+            builder
+                .push(" AND length_seconds >= ")
+                .push_bind(min_length);
+        }
+        if let Some(max_length) = query.max_length_seconds {
+            builder
+                .push(" AND length_seconds <= ")
+                .push_bind(max_length);
+        }
+        if let Some(min_notes) = query.min_note_count {
+            builder.push(" AND note_count >= ").push_bind(min_notes);
+        }
+        if let Some(max_notes) = query.max_note_count {
+            builder.push(" AND note_count <= ").push_bind(max_notes);
+        }
+        if let Some(piece) = query.piece_id {
+            builder.push(" AND piece_id = ").push_bind(piece);
+        }
+        if !query.include_short {
+            builder.push(" AND NOT short");
+        }
+        if let Some(client_name) = query.device_client_name {
+            builder
+                .push(" AND device_client_name = ")
+                .push_bind(client_name);
+        }
+        if let Some(port_name) = query.device_port_name {
+            builder
+                .push(" AND device_port_name = ")
+                .push_bind(port_name);
+        }
+        // Tags are compared case-insensitively, so duplicates differing only in case or whitespace
+        // would never all match in the `HAVING COUNT(*)` below
+        let mut tags = query
+            .tags
+            .iter()
+            .map(|tag| tag.trim().to_ascii_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        if !tags.is_empty() {
+            let tag_count = tags.len() as u32;
+            builder.push(
+                " AND id IN (
+                    SELECT recording_id FROM recording_tags
+                        JOIN tags ON tags.id = recording_tags.tag_id
+                        WHERE tags.name IN (",
+            );
+            let mut separated = builder.separated(", ");
+            for tag in tags {
+                separated.push_bind(tag);
+            }
+            builder
+                .push(") GROUP BY recording_id HAVING COUNT(*) = ")
+                .push_bind(tag_count)
+                .push(")");
+        }
+        if let Some(cursor) = query.cursor.as_deref() {
+            // Keyset pagination: continue strictly after the (key, id) pair of the cursor
+            let (key, id) = CursorKey::decode(query.sort, cursor)?;
+            builder.push(format!(" AND ({column}, id) {cmp} ("));
+            key.push_bind(&mut builder);
+            builder.push(", ").push_bind(id).push(")");
+        }
+
+        builder.push(format!(" ORDER BY {column} {dir}, id {dir} LIMIT "));
+        // Fetch one more to find out whether there is a next page
+        builder.push_bind(limit + 1);
+
+        let mut recordings = builder
+            .build()
+            .try_map(|row| RecordingInfo::from_row(&row))
+            .fetch_all(&self.pool)
+            .await?;
+
+        let next_cursor = if recordings.len() > limit as usize {
+            recordings.truncate(limit as usize);
+            recordings
+                .last()
+                .map(|last| CursorKey::of(query.sort, last).encode(last.id))
+        } else {
+            None
+        };
+
+        Ok(RecordingPage {
+            recordings,
+            next_cursor,
+        })
+    }
+
+    /// Full-text search over names and notes of the recordings, best matches first.
+    ///
+    /// Every word of the search string is matched as a prefix, i.e. `chop noc` finds
+    /// "Chopin - Nocturne Op. 9 No. 2". Short takes are only found with `include_short`.
+    pub async fn search_recording_infos(
+        &self,
+        search: &str,
+        include_short: bool,
+        limit: u32,
+    ) -> color_eyre::Result<Vec<RecordingInfo>> {
+        let fts_query = search
+            .split_whitespace()
+            .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if fts_query.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let recordings = sqlx::query_as::<_, RecordingInfo>(&format!(
+            "SELECT {RECORDING_INFO_COLUMNS} FROM recordings
+                JOIN (
+                    SELECT rowid AS match_id, rank FROM recordings_fts WHERE recordings_fts MATCH ?
+                ) ON id = match_id
+                WHERE deleted_at IS NULL AND (? OR NOT short)
+                ORDER BY rank
+                LIMIT ?"
+        ))
+        .bind(fts_query)
+        .bind(include_short)
+        .bind(limit.clamp(1, MAX_PAGE_SIZE))
         .fetch_all(&self.pool)
         .await?;
         Ok(recordings)
@@ -77,45 +568,316 @@ impl RecordingStore {
         &self,
         id: RecordingId,
     ) -> color_eyre::Result<RecordingInfo> {
-        let recording = sqlx::query_as::<_, RecordingInfo>(
-            "SELECT id, name, created_at, length_seconds, note_count FROM recordings WHERE id = ?",
-        )
+        let recording = sqlx::query_as::<_, RecordingInfo>(&format!(
+            "SELECT {RECORDING_INFO_COLUMNS} FROM recordings WHERE id = ?"
+        ))
         .bind(id)
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
+        recording.ok_or_else(|| RecordingError::NotFound(id).into())
+    }
+
+    /// Like [`Self::get_recording_info_by_id`], but fails for recordings in the trash.
+    pub async fn get_active_recording_info_by_id(
+        &self,
+        id: RecordingId,
+    ) -> color_eyre::Result<RecordingInfo> {
+        let recording = self.get_recording_info_by_id(id).await?;
+        if recording.deleted_at.is_some() {
+            return Err(RecordingError::Trashed(id).into());
+        }
         Ok(recording)
     }
 
+    /// Return the recordings in the trash, most recently deleted first.
+    pub async fn get_trashed_recording_infos(&self) -> color_eyre::Result<Vec<RecordingInfo>> {
+        let recordings = sqlx::query_as::<_, RecordingInfo>(&format!(
+            "SELECT {RECORDING_INFO_COLUMNS} FROM recordings
+                WHERE deleted_at IS NOT NULL
+                ORDER BY deleted_at DESC"
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(recordings)
+    }
+
+    /// Move a recording to the trash. It can be restored until it gets purged.
     pub async fn delete_recording_by_id(&self, id: RecordingId) -> color_eyre::Result<()> {
-        let recording = sqlx::query("DELETE FROM recordings WHERE id = ?")
-            .bind(id)
-            .execute(&self.pool)
-            .await?;
+        let recording =
+            sqlx::query("UPDATE recordings SET deleted_at = ? WHERE id = ? AND deleted_at IS NULL")
+                .bind(Utc::now())
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+        if recording.rows_affected() == 0 {
+            self.check_recording_exists(&self.pool, id).await?;
+        }
+        Ok(())
+    }
+
+    /// Take a recording back out of the trash.
+    pub async fn restore_recording_by_id(&self, id: RecordingId) -> color_eyre::Result<()> {
+        let recording = sqlx::query(
+            "UPDATE recordings SET deleted_at = NULL WHERE id = ? AND deleted_at IS NOT NULL",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
         if recording.rows_affected() == 0 {
-            bail!("No recording found with id {}", id.0)
+            bail!("No recording found in trash with id {}", id.0)
         }
         Ok(())
     }
 
+    /// Permanently delete all recordings that were moved to the trash before the given time.
+    pub async fn purge_trash(&self, deleted_before: DateTime<Utc>) -> color_eyre::Result<u64> {
+        let result = sqlx::query("DELETE FROM recordings WHERE deleted_at < ?")
+            .bind(deleted_before)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
     pub async fn rename_recording_by_id(
         &self,
         id: RecordingId,
         new_name: String,
     ) -> color_eyre::Result<()> {
-        let recording = sqlx::query("UPDATE recordings SET name = ? WHERE id = ?")
-            .bind(new_name)
+        let recording =
+            sqlx::query("UPDATE recordings SET name = ? WHERE id = ? AND deleted_at IS NULL")
+                .bind(new_name)
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+        if recording.rows_affected() == 0 {
+            self.check_recording_exists(&self.pool, id).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn set_recording_notes_by_id(
+        &self,
+        id: RecordingId,
+        notes: String,
+    ) -> color_eyre::Result<()> {
+        let recording =
+            sqlx::query("UPDATE recordings SET notes = ? WHERE id = ? AND deleted_at IS NULL")
+                .bind(notes)
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+        if recording.rows_affected() == 0 {
+            self.check_recording_exists(&self.pool, id).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn assign_recording_to_piece(
+        &self,
+        id: RecordingId,
+        piece: Option<PieceId>,
+    ) -> color_eyre::Result<()> {
+        let recording =
+            sqlx::query("UPDATE recordings SET piece_id = ? WHERE id = ? AND deleted_at IS NULL")
+                .bind(piece)
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+        if recording.rows_affected() == 0 {
+            self.check_recording_exists(&self.pool, id).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn add_recording_tag(&self, id: RecordingId, tag: &str) -> color_eyre::Result<()> {
+        let tag = normalize_tag(tag)?;
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query("INSERT INTO tags (name) VALUES (?) ON CONFLICT (name) DO NOTHING")
+            .bind(tag)
+            .execute(&mut transaction)
+            .await?;
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO recording_tags (recording_id, tag_id)
+                SELECT recordings.id, tags.id FROM recordings, tags
+                    WHERE recordings.id = ? AND recordings.deleted_at IS NULL AND tags.name = ?",
+        )
+        .bind(id)
+        .bind(tag)
+        .execute(&mut transaction)
+        .await?;
+        if result.rows_affected() == 0 {
+            // Either the recording doesn't exist or is in the trash, or it was already tagged
+            self.check_recording_exists(&mut *transaction, id).await?;
+        }
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    pub async fn remove_recording_tag(&self, id: RecordingId, tag: &str) -> color_eyre::Result<()> {
+        let tag = normalize_tag(tag)?;
+        let result = sqlx::query(
+            "DELETE FROM recording_tags
+                WHERE recording_id = (SELECT id FROM recordings WHERE id = ? AND deleted_at IS NULL)
+                    AND tag_id = (SELECT id FROM tags WHERE name = ?)",
+        )
+        .bind(id)
+        .bind(tag)
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            self.check_recording_exists(&self.pool, id).await?;
+            bail!("Recording {} is not tagged with {:?}", id.0, tag)
+        }
+        Ok(())
+    }
+
+    /// Return all tags that are in use, together with the number of recordings they are used on.
+    pub async fn get_tags(&self) -> color_eyre::Result<Vec<TagCount>> {
+        let tags = sqlx::query_as::<_, TagCount>(
+            "SELECT tags.name AS name, COUNT(*) AS recording_count FROM tags
+                JOIN recording_tags ON recording_tags.tag_id = tags.id
+                JOIN recordings ON recordings.id = recording_tags.recording_id
+                WHERE recordings.deleted_at IS NULL
+                GROUP BY tags.id
+                ORDER BY tags.name",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tags)
+    }
+
+    pub async fn get_library_stats(&self) -> color_eyre::Result<LibraryStats> {
+        let stats = sqlx::query_as::<_, LibraryStats>(
+            r"
+            SELECT
+                COUNT(*) AS recording_count,
+                TOTAL(length_seconds) AS total_length_seconds,
+                COALESCE(SUM(note_count), 0) AS total_note_count,
+                MIN(created_at) AS first_recording_at,
+                MAX(created_at) AS last_recording_at,
+                (SELECT COUNT(*) FROM recordings WHERE deleted_at IS NOT NULL) AS trashed_count,
+                (SELECT COUNT(*) FROM pieces) AS piece_count,
+                (
+                    SELECT COUNT(DISTINCT tag_id) FROM recording_tags
+                        JOIN recordings AS tagged ON tagged.id = recording_tags.recording_id
+                        WHERE tagged.deleted_at IS NULL
+                ) AS tag_count
+            FROM recordings
+            WHERE deleted_at IS NULL
+        ",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(stats)
+    }
+
+    /// Fail with a [`RecordingError`] if the recording doesn't exist or is in the trash.
+    async fn check_recording_exists<'e, E>(
+        &self,
+        executor: E,
+        id: RecordingId,
+    ) -> color_eyre::Result<()>
+    where
+        E: sqlx::Executor<'e, Database = Sqlite>,
+    {
+        let trashed = sqlx::query_scalar::<_, bool>(
+            "SELECT deleted_at IS NOT NULL FROM recordings WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_optional(executor)
+        .await?;
+        match trashed {
+            None => Err(RecordingError::NotFound(id).into()),
+            Some(true) => Err(RecordingError::Trashed(id).into()),
+            Some(false) => Ok(()),
+        }
+    }
+
+    pub async fn get_pieces(&self) -> color_eyre::Result<Vec<Piece>> {
+        let pieces = sqlx::query_as::<_, Piece>(
+            "SELECT id, title, composer, catalogue_number FROM pieces ORDER BY title, composer, id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(pieces)
+    }
+
+    pub async fn get_piece_by_id(&self, id: PieceId) -> color_eyre::Result<Piece> {
+        let piece = sqlx::query_as::<_, Piece>(
+            "SELECT id, title, composer, catalogue_number FROM pieces WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        piece.ok_or_else(|| eyre!("No piece found with id {}", id.0))
+    }
+
+    pub async fn insert_piece(
+        &self,
+        title: String,
+        composer: String,
+        catalogue_number: Option<String>,
+    ) -> color_eyre::Result<Piece> {
+        let mut transaction = self.pool.begin().await?;
+        let piece = sqlx::query_as::<_, Piece>(
+            "INSERT INTO pieces (title, composer, catalogue_number)
+                VALUES (?, ?, ?)
+                RETURNING id, title, composer, catalogue_number",
+        )
+        .bind(title)
+        .bind(composer)
+        .bind(catalogue_number)
+        .fetch_one(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(piece)
+    }
+
+    pub async fn update_piece(
+        &self,
+        id: PieceId,
+        title: String,
+        composer: String,
+        catalogue_number: Option<String>,
+    ) -> color_eyre::Result<Piece> {
+        let mut transaction = self.pool.begin().await?;
+        let piece = sqlx::query_as::<_, Piece>(
+            "UPDATE pieces SET title = ?, composer = ?, catalogue_number = ?
+                WHERE id = ?
+                RETURNING id, title, composer, catalogue_number",
+        )
+        .bind(title)
+        .bind(composer)
+        .bind(catalogue_number)
+        .bind(id)
+        .fetch_optional(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        piece.ok_or_else(|| eyre!("No piece found with id {}", id.0))
+    }
+
+    /// Delete a piece. Recordings assigned to it become unassigned.
+    pub async fn delete_piece_by_id(&self, id: PieceId) -> color_eyre::Result<()> {
+        let piece = sqlx::query("DELETE FROM pieces WHERE id = ?")
             .bind(id)
             .execute(&self.pool)
             .await?;
-        if recording.rows_affected() == 0 {
-            bail!("No recording found with id {}", id.0)
+        if piece.rows_affected() == 0 {
+            bail!("No piece found with id {}", id.0)
         }
         Ok(())
     }
 
     pub async fn insert_recording(
         &self,
-        midi: midly::Smf<'static>,
+        midi: midly::Smf<'_>,
+        created_at: DateTime<Utc>,
+        stop_reason: Option<StopReason>,
+        device: Option<&DeviceInfo>,
+        recovered: bool,
+        short: bool,
     ) -> color_eyre::Result<RecordingInfo> {
         let mut midi_data = vec![];
         midi.write_std(&mut midi_data)
@@ -127,31 +889,118 @@ impl RecordingStore {
             .first()
             .map_or((std::time::Duration::default(), 0), compute_midi_stats);
 
-        let rec = sqlx::query_as::<_, RecordingInfo>(
-            "INSERT INTO recordings (created_at, length_seconds, note_count, midi)
-                VALUES (?, ?, ?, ?)
-                RETURNING id, name, created_at, length_seconds, note_count",
+        // Statements with a RETURNING clause only complete once all rows have been fetched, so use
+        // an explicit transaction to know when the write is actually committed
+        let mut transaction = self.pool.begin().await?;
+        // NOTE: Only the id is returned, since lengths of whole seconds come back from RETURNING as
+        // INTEGER, which fails to decode
+        let (id,) = sqlx::query_as::<_, (RecordingId,)>(
+            "INSERT INTO recordings (
+                    created_at, length_seconds, note_count, midi, stop_reason,
+                    device_client_name, device_port_name, recovered, short
+                )
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                RETURNING id",
         )
-        .bind(Utc::now())
+        .bind(created_at)
         .bind(length.as_secs_f64())
         .bind(u32::try_from(note_count).unwrap_or(u32::MAX))
         .bind(compressed_midi)
-        .fetch_one(&self.pool)
+        .bind(stop_reason)
+        .bind(device.map(|device| device.client_name.as_str()))
+        .bind(device.map(|device| device.port_name.as_str()))
+        .bind(recovered)
+        .bind(short)
+        .fetch_one(&mut transaction)
         .await?;
-        Ok(rec)
+        transaction.commit().await?;
+        self.get_recording_info_by_id(id).await
     }
 
     pub async fn get_recording_midi(&self, id: RecordingId) -> color_eyre::Result<Vec<u8>> {
         let (compressed_midi,) =
             sqlx::query_as::<_, (Vec<u8>,)>("SELECT midi FROM recordings WHERE id = ?")
                 .bind(id)
-                .fetch_one(&self.pool)
-                .await?;
+                .fetch_optional(&self.pool)
+                .await?
+                .ok_or(RecordingError::NotFound(id))?;
         let midi = decompress_midi(compressed_midi);
         Ok(midi)
     }
 }
 
+/// Exclusive use of the database in a data directory, e.g. by the daemon while it runs, so that
+/// the database isn't replaced from under it. Released when dropped.
+#[derive(Debug)]
+pub struct DataDirectoryLock {
+    #[allow(unused)]
+    file: std::fs::File,
+}
+
+impl DataDirectoryLock {
+    /// Take the lock, failing right away if it is held already.
+    pub fn acquire(directory: &Path) -> color_eyre::Result<Self> {
+        let path = directory.join("autorec.lock");
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .wrap_err_with(|| format!("opening {}", path.display()))?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(Self { file }),
+            Err(Errno::EWOULDBLOCK) => bail!(
+                "'{}' is locked, the database is in use. Is the daemon still running?",
+                path.display()
+            ),
+            Err(err) => Err(err).wrap_err_with(|| format!("locking {}", path.display())),
+        }
+    }
+}
+
+/// Check that the file is an intact autorec database that can be opened by this version.
+///
+/// Returns the schema version of the database.
+pub async fn check_database(path: &Path) -> color_eyre::Result<i32> {
+    let conn_opts = SqliteConnectOptions::new().filename(path).read_only(true);
+    let pool = SqlitePoolOptions::new().connect_with(conn_opts).await?;
+
+    let integrity = sqlx::query_scalar::<_, String>("PRAGMA integrity_check")
+        .fetch_all(&pool)
+        .await?;
+    if integrity != ["ok"] {
+        bail!("Database is corrupted: {}", integrity.join("; "));
+    }
+
+    let version = sqlx::query_scalar::<_, Option<i32>>("SELECT MAX(id) AS version FROM migrations")
+        .fetch_one(&pool)
+        .await
+        .map_err(|_| eyre!("Not an autorec database"))?
+        .ok_or_else(|| eyre!("Not an autorec database"))?;
+    pool.close().await;
+
+    if version > LATEST_VERSION {
+        bail!("Database version {} is too new", version);
+    }
+    Ok(version)
+}
+
+async fn vacuum_into(pool: &SqlitePool, path: &Path) -> color_eyre::Result<()> {
+    if path.exists() {
+        bail!("Backup file '{}' already exists", path.display());
+    }
+    let path = path
+        .to_str()
+        .ok_or_else(|| eyre!("Backup path '{}' is not valid UTF-8", path.display()))?;
+    sqlx::query("VACUUM INTO ?")
+        .bind(path)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+const LATEST_VERSION: i32 = 10;
+
 async fn migrate(pool: &SqlitePool, directory: &Path) -> color_eyre::Result<()> {
     info!("Checking for migrations");
 
@@ -174,18 +1023,20 @@ async fn migrate(pool: &SqlitePool, directory: &Path) -> color_eyre::Result<()>
 
     info!("Database version: {:?}", version);
 
-    const LATEST_VERSION: i32 = 2;
-
     loop {
         if let Some(version) = version {
             if version < LATEST_VERSION {
                 // create a backup
-                let orig = directory.join("autorec.db");
-                let backup = directory.join(format!("autorec.db.v{}", version));
+                let mut backup = directory.join(format!("autorec.db.v{}", version));
                 if backup.exists() {
-                    bail!("Backup file '{}' already exists", backup.display());
+                    // Happens after restoring an old database from a backup
+                    backup = directory.join(format!(
+                        "autorec.db.v{}.{}",
+                        version,
+                        Utc::now().format("%Y%m%dT%H%M%SZ")
+                    ));
                 }
-                std::fs::copy(orig, backup)?;
+                vacuum_into(pool, &backup).await?;
             }
         }
 
@@ -198,6 +1049,14 @@ async fn migrate(pool: &SqlitePool, directory: &Path) -> color_eyre::Result<()>
                 migrate_001_inline_midi_storage_and_meta(&mut transaction, directory).await?
             }
             Some(1) => migrate_002_fix_length_seconds(&mut transaction).await?,
+            Some(2) => migrate_003_full_text_search(&mut transaction).await?,
+            Some(3) => migrate_004_pieces(&mut transaction).await?,
+            Some(4) => migrate_005_tags(&mut transaction).await?,
+            Some(5) => migrate_006_trash(&mut transaction).await?,
+            Some(6) => migrate_007_recovered(&mut transaction).await?,
+            Some(7) => migrate_008_stop_reason(&mut transaction).await?,
+            Some(8) => migrate_009_short(&mut transaction).await?,
+            Some(9) => migrate_010_device(&mut transaction).await?,
             Some(LATEST_VERSION) => {
                 debug!("No more migrations");
                 break;
@@ -393,6 +1252,220 @@ async fn migrate_002_fix_length_seconds(
     Ok(())
 }
 
+/// Add free-text notes to recordings, and an FTS5 index over names and notes for searching.
+///
+/// The index is an external content table, kept in sync with `recordings` via triggers.
+async fn migrate_003_full_text_search(
+    transaction: &mut Transaction<'_, Sqlite>,
+) -> color_eyre::Result<()> {
+    sqlx::query("ALTER TABLE recordings ADD COLUMN notes TEXT NOT NULL DEFAULT ''")
+        .execute(&mut *transaction)
+        .await?;
+
+    sqlx::query(
+        r"
+        CREATE VIRTUAL TABLE recordings_fts USING fts5(
+            name,
+            notes,
+            content = 'recordings',
+            content_rowid = 'id',
+            tokenize = 'unicode61 remove_diacritics 2'
+        )
+    ",
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TRIGGER recordings_fts_insert AFTER INSERT ON recordings BEGIN
+            INSERT INTO recordings_fts (rowid, name, notes) VALUES (new.id, new.name, new.notes);
+        END
+    ",
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TRIGGER recordings_fts_delete AFTER DELETE ON recordings BEGIN
+            INSERT INTO recordings_fts (recordings_fts, rowid, name, notes)
+                VALUES ('delete', old.id, old.name, old.notes);
+        END
+    ",
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TRIGGER recordings_fts_update AFTER UPDATE OF name, notes ON recordings BEGIN
+            INSERT INTO recordings_fts (recordings_fts, rowid, name, notes)
+                VALUES ('delete', old.id, old.name, old.notes);
+            INSERT INTO recordings_fts (rowid, name, notes) VALUES (new.id, new.name, new.notes);
+        END
+    ",
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    info!("Indexing existing recordings");
+
+    sqlx::query("INSERT INTO recordings_fts (recordings_fts) VALUES ('rebuild')")
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+/// Introduce pieces as the identity of what was played, rather than relying on the free-text name
+/// of a recording. A piece is created for every distinct name that is already in use.
+async fn migrate_004_pieces(transaction: &mut Transaction<'_, Sqlite>) -> color_eyre::Result<()> {
+    sqlx::query(
+        r"
+        CREATE TABLE pieces (
+            id INTEGER PRIMARY KEY NOT NULL,
+            title TEXT NOT NULL,
+            composer TEXT NOT NULL DEFAULT '',
+            catalogue_number TEXT
+        )
+    ",
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        "ALTER TABLE recordings ADD COLUMN piece_id INTEGER REFERENCES pieces(id) ON DELETE SET NULL",
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    // Keep ids in order of the first time a piece was recorded
+    let res = sqlx::query(
+        r"
+        INSERT INTO pieces (title)
+            SELECT name FROM recordings
+                WHERE name != ''
+                GROUP BY name
+                ORDER BY MIN(created_at)
+    ",
+    )
+    .execute(&mut *transaction)
+    .await?;
+    info!("Created {} pieces", res.rows_affected());
+
+    sqlx::query(
+        r"
+        UPDATE recordings
+            SET piece_id = (SELECT id FROM pieces WHERE title = recordings.name)
+            WHERE name != ''
+    ",
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Allow tagging recordings with arbitrary labels, independently of their name and piece.
+async fn migrate_005_tags(transaction: &mut Transaction<'_, Sqlite>) -> color_eyre::Result<()> {
+    sqlx::query(
+        r"
+        CREATE TABLE tags (
+            id INTEGER PRIMARY KEY NOT NULL,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE
+        )
+    ",
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r"
+        CREATE TABLE recording_tags (
+            recording_id INTEGER NOT NULL REFERENCES recordings(id) ON DELETE CASCADE,
+            tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+            PRIMARY KEY (recording_id, tag_id)
+        )
+    ",
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query("CREATE INDEX recording_tags_by_tag ON recording_tags (tag_id)")
+        .execute(&mut *transaction)
+        .await?;
+
+    Ok(())
+}
+
+/// Deleting a recording only moves it to the trash, from where it is purged after a while.
+async fn migrate_006_trash(transaction: &mut Transaction<'_, Sqlite>) -> color_eyre::Result<()> {
+    sqlx::query("ALTER TABLE recordings ADD COLUMN deleted_at TEXT")
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("CREATE INDEX recordings_by_deleted_at ON recordings (deleted_at)")
+        .execute(&mut *transaction)
+        .await?;
+    Ok(())
+}
+
+/// Flag recordings that were recovered from the journal of an interrupted recording session.
+async fn migrate_007_recovered(
+    transaction: &mut Transaction<'_, Sqlite>,
+) -> color_eyre::Result<()> {
+    sqlx::query("ALTER TABLE recordings ADD COLUMN recovered BOOLEAN NOT NULL DEFAULT FALSE")
+        .execute(&mut *transaction)
+        .await?;
+    Ok(())
+}
+
+/// Remember what ended each recording, e.g. an idle gap or a gesture.
+async fn migrate_008_stop_reason(
+    transaction: &mut Transaction<'_, Sqlite>,
+) -> color_eyre::Result<()> {
+    sqlx::query("ALTER TABLE recordings ADD COLUMN stop_reason TEXT")
+        .execute(&mut *transaction)
+        .await?;
+    Ok(())
+}
+
+/// Flag recordings that are too short to be worth listing by default.
+async fn migrate_009_short(transaction: &mut Transaction<'_, Sqlite>) -> color_eyre::Result<()> {
+    sqlx::query("ALTER TABLE recordings ADD COLUMN short BOOLEAN NOT NULL DEFAULT FALSE")
+        .execute(&mut *transaction)
+        .await?;
+    Ok(())
+}
+
+/// Remember which MIDI device each recording was made on.
+async fn migrate_010_device(transaction: &mut Transaction<'_, Sqlite>) -> color_eyre::Result<()> {
+    sqlx::query("ALTER TABLE recordings ADD COLUMN device_client_name TEXT")
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("ALTER TABLE recordings ADD COLUMN device_port_name TEXT")
+        .execute(&mut *transaction)
+        .await?;
+    Ok(())
+}
+
+/// Tags are compared case-insensitively, but surrounding whitespace is never significant.
+fn normalize_tag(tag: &str) -> color_eyre::Result<&str> {
+    let tag = tag.trim();
+    if tag.is_empty() || tag.contains(TAG_SEPARATOR) {
+        bail!("Invalid tag {:?}", tag)
+    }
+    Ok(tag)
+}
+
+/// Escape the wildcards of a `LIKE` pattern, for use with `ESCAPE '\'`.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
 fn compute_midi_stats(track: &midly::Track) -> (std::time::Duration, usize) {
     let length_ticks = track.iter().map(|event| event.delta.as_int()).sum::<u32>();
     let length = std::time::Duration::from_micros(
@@ -421,3 +1494,230 @@ fn compress_midi<T: AsRef<[u8]>>(midi: T) -> Vec<u8> {
 fn decompress_midi<T: AsRef<[u8]>>(midi: T) -> Vec<u8> {
     zstd::decode_all(midi.as_ref()).expect("decompressing in memory should not fail")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store in a fresh directory of its own.
+    async fn open_store(name: &str) -> RecordingStore {
+        let directory =
+            std::env::temp_dir().join(format!("autorec-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        RecordingStore::open(&directory).await.unwrap()
+    }
+
+    /// A single note lasting `ticks`.
+    fn single_note(ticks: u32) -> midly::Smf<'static> {
+        let mut smf = midly::Smf::new(midly::Header::new(
+            midly::Format::SingleTrack,
+            midly::Timing::Metrical(midly::num::u15::new(RECORDING_PPQ)),
+        ));
+        let note = |delta: u32, message| midly::TrackEvent {
+            delta: delta.into(),
+            kind: midly::TrackEventKind::Midi {
+                channel: 0.into(),
+                message,
+            },
+        };
+        smf.tracks.push(vec![
+            note(
+                0,
+                midly::MidiMessage::NoteOn {
+                    key: 60.into(),
+                    vel: 80.into(),
+                },
+            ),
+            note(
+                ticks,
+                midly::MidiMessage::NoteOff {
+                    key: 60.into(),
+                    vel: 0.into(),
+                },
+            ),
+            midly::TrackEvent {
+                delta: 0.into(),
+                kind: midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+            },
+        ]);
+        smf
+    }
+
+    #[tokio::test]
+    async fn insert_recording_of_whole_seconds() {
+        let store = open_store("whole-seconds").await;
+        // Two beats, i.e. exactly one second
+        let midi = single_note(2 * u32::from(RECORDING_PPQ));
+        let rec = store
+            .insert_recording(midi, Utc::now(), None, None, false, false)
+            .await
+            .unwrap();
+        assert_eq!(rec.length_seconds, 1.0);
+        let stored = store.get_recording_info_by_id(rec.id).await.unwrap();
+        assert_eq!(stored.length_seconds, 1.0);
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let created_at = Utc.from_utc_datetime(
+            &chrono::NaiveDate::from_ymd_opt(2022, 7, 14)
+                .and_then(|date| date.and_hms_nano_opt(18, 30, 5, 123_456_789))
+                .unwrap(),
+        );
+        let keys = [
+            (RecordingSort::CreatedAt, CursorKey::CreatedAt(created_at)),
+            (
+                RecordingSort::Name,
+                CursorKey::Name("Op. 9: No. 2".to_owned()),
+            ),
+            (RecordingSort::Name, CursorKey::Name(String::new())),
+            (RecordingSort::LengthSeconds, CursorKey::LengthSeconds(0.1)),
+            (RecordingSort::NoteCount, CursorKey::NoteCount(1234)),
+        ];
+        for (sort, key) in keys {
+            let cursor = key.encode(RecordingId(42));
+            let (decoded, id) = CursorKey::decode(sort, &cursor).unwrap();
+            assert_eq!(decoded, key);
+            assert_eq!(id, RecordingId(42));
+        }
+    }
+
+    #[test]
+    fn invalid_cursor() {
+        for cursor in ["", "42", "x:1", "42:yesterday"] {
+            assert!(CursorKey::decode(RecordingSort::CreatedAt, cursor).is_err());
+        }
+        assert!(CursorKey::decode(RecordingSort::NoteCount, "42:-1").is_err());
+    }
+
+    #[tokio::test]
+    async fn name_filter_matches_wildcards_literally() {
+        let store = open_store("name-wildcards").await;
+        for name in ["100%", "1000", "a_b", "axb"] {
+            let rec = store
+                .insert_recording(single_note(1), Utc::now(), None, None, false, false)
+                .await
+                .unwrap();
+            store
+                .rename_recording_by_id(rec.id, name.to_owned())
+                .await
+                .unwrap();
+        }
+
+        for (filter, expected) in [("0%", "100%"), ("a_", "a_b")] {
+            let page = store
+                .query_recording_infos(RecordingQuery {
+                    name: Some(filter.to_owned()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let names = page
+                .recordings
+                .iter()
+                .map(|rec| rec.name.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(names, [expected]);
+        }
+    }
+
+    #[tokio::test]
+    async fn tag_filter_ignores_case_and_duplicates() {
+        let store = open_store("tag-filter").await;
+        let rec = store
+            .insert_recording(single_note(1), Utc::now(), None, None, false, false)
+            .await
+            .unwrap();
+        store.add_recording_tag(rec.id, "Foo").await.unwrap();
+
+        let page = store
+            .query_recording_infos(RecordingQuery {
+                tags: vec!["foo".to_owned(), " FOO ".to_owned()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let ids = page.recordings.iter().map(|rec| rec.id).collect::<Vec<_>>();
+        assert_eq!(ids, [rec.id]);
+    }
+
+    #[tokio::test]
+    async fn trashed_recordings_cannot_be_changed() {
+        let store = open_store("trashed").await;
+        let rec = store
+            .insert_recording(single_note(1), Utc::now(), None, None, false, false)
+            .await
+            .unwrap();
+        store.add_recording_tag(rec.id, "foo").await.unwrap();
+        store.delete_recording_by_id(rec.id).await.unwrap();
+
+        assert!(store.get_tags().await.unwrap().is_empty());
+        let stats = store.get_library_stats().await.unwrap();
+        assert_eq!((stats.recording_count, stats.trashed_count), (0, 1));
+        assert_eq!(stats.tag_count, 0);
+        let error = store
+            .rename_recording_by_id(rec.id, "New name".to_owned())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<RecordingError>(),
+            Some(&RecordingError::Trashed(rec.id))
+        );
+        let error = store.add_recording_tag(rec.id, "bar").await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<RecordingError>(),
+            Some(&RecordingError::Trashed(rec.id))
+        );
+
+        let missing = RecordingId(rec.id.0 + 1);
+        let error = store
+            .rename_recording_by_id(missing, "New name".to_owned())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<RecordingError>(),
+            Some(&RecordingError::NotFound(missing))
+        );
+    }
+
+    #[tokio::test]
+    async fn short_takes_are_hidden_by_default() {
+        let store = open_store("short-takes").await;
+        let mut ids = vec![];
+        for short in [false, true] {
+            let rec = store
+                .insert_recording(single_note(1), Utc::now(), None, None, false, short)
+                .await
+                .unwrap();
+            assert_eq!(rec.short, short);
+            store
+                .rename_recording_by_id(rec.id, "Nocturne".to_owned())
+                .await
+                .unwrap();
+            ids.push(rec.id);
+        }
+
+        for include_short in [false, true] {
+            let expected = if include_short { &ids[..] } else { &ids[..1] };
+            let page = store
+                .query_recording_infos(RecordingQuery {
+                    include_short,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let mut listed = page.recordings.iter().map(|rec| rec.id).collect::<Vec<_>>();
+            listed.sort_by_key(|id| id.0);
+            assert_eq!(listed, expected);
+
+            let found = store
+                .search_recording_infos("noc", include_short, 10)
+                .await
+                .unwrap();
+            let mut found = found.iter().map(|rec| rec.id).collect::<Vec<_>>();
+            found.sort_by_key(|id| id.0);
+            assert_eq!(found, expected);
+        }
+    }
+}